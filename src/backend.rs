@@ -0,0 +1,450 @@
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+use crate::bootargs;
+use crate::config::{resolve_mounts, CozyBootConfig, ResolvedMount};
+use crate::plan::BootPlan;
+use crate::runtime::{self, RuntimeConfig};
+use crate::supervisor;
+use crate::vars::{expand_variables, Variables};
+
+/// Which launcher turns a resolved boot into a running process
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BackendKind {
+    /// The cozy-os command-line interface
+    #[default]
+    CozyOs,
+    /// An argv pattern from `[runtime] template`
+    Template,
+}
+
+/// Everything needed to boot, with variables expanded and paths validated.
+/// Backends decide how this is turned into a command line.
+#[derive(Debug, Clone)]
+pub struct ResolvedBoot {
+    pub executable: PathBuf,
+    pub kern_root: String,
+    pub user_root: String,
+    /// Rendered `[bootargs]` flags in command-line order
+    pub bootargs: Vec<String>,
+    pub mounts: Vec<ResolvedMount>,
+    pub allow_32bit: Option<bool>,
+    pub allow_universal: Option<bool>,
+    pub allow_64bit: Option<bool>,
+    pub boot_string: Option<String>,
+    pub debug: bool,
+}
+
+/// Per-invocation options that are not part of the config file
+#[derive(Debug, Clone, Default)]
+pub struct BootOptions {
+    /// Boot string to pass directly to CozyOS
+    pub boot_string: Option<String>,
+    pub debug: bool,
+    /// cozy-os executable overriding `[runtime] executable`
+    pub executable: Option<String>,
+}
+
+impl ResolvedBoot {
+    /// Resolve everything needed to boot from a validated config
+    pub fn resolve(
+        config: &CozyBootConfig,
+        config_path: &Path,
+        options: &BootOptions,
+    ) -> Result<Self, String> {
+        let vars = Variables::new(&config.vars, &config.main.kern_root, config_path);
+        let expand = |field: &str, value: &str| {
+            expand_variables(value, &vars)
+                .map_err(|e| format!("failed to expand {} '{}': {}", field, value, e))
+        };
+
+        let kern_root = expand("main.kern_root", &config.main.kern_root)?;
+        let user_root = expand("main.user_root", &config.main.user_root)?;
+        let mounts = resolve_mounts(&config.mounts, &vars).map_err(|errors| {
+            errors
+                .into_iter()
+                .map(|error| error.message)
+                .collect::<Vec<_>>()
+                .join("; ")
+        })?;
+
+        let executable = runtime::resolve_executable(
+            options
+                .executable
+                .as_deref()
+                .or(config.runtime.executable.as_deref()),
+            &vars,
+        )?;
+
+        let mut rendered = Vec::new();
+        for (key, arg) in bootargs::ordered(&config.bootargs) {
+            let flags = arg
+                .render(key, &|value| vars.expand(value))
+                .map_err(|e| format!("failed to expand bootargs.{}: {}", key, e))?;
+            rendered.extend(flags);
+        }
+
+        Ok(ResolvedBoot {
+            executable,
+            kern_root,
+            user_root,
+            bootargs: rendered,
+            mounts,
+            allow_32bit: config.bin.allow_32bit,
+            allow_universal: config.bin.allow_universal,
+            allow_64bit: config.bin.allow_64bit,
+            boot_string: options.boot_string.clone(),
+            debug: options.debug,
+        })
+    }
+
+    fn mount_args(&self) -> Vec<String> {
+        self.mounts
+            .iter()
+            .flat_map(|mount| ["--mount".to_string(), mount.to_arg()])
+            .collect()
+    }
+
+    fn bin_args(&self) -> Vec<String> {
+        [
+            ("--allow-32bit", self.allow_32bit),
+            ("--allow-universal", self.allow_universal),
+            ("--allow-64bit", self.allow_64bit),
+        ]
+        .into_iter()
+        .filter_map(|(flag, value)| value.map(|value| [flag.to_string(), value.to_string()]))
+        .flatten()
+        .collect()
+    }
+
+    /// The cozy-os arguments for this boot, without the program name
+    pub fn cozy_os_args(&self) -> Vec<String> {
+        // Set kernel and user roots with expanded paths
+        let mut args = vec![
+            "--kern-root".to_string(),
+            self.kern_root.clone(),
+            "--user-root".to_string(),
+            self.user_root.clone(),
+        ];
+
+        // Add boot arguments and shared host directories
+        args.extend(self.bootargs.iter().cloned());
+        args.extend(self.mount_args());
+
+        // Configure binary settings
+        args.extend(self.bin_args());
+
+        // Add boot string if provided
+        if let Some(boot_string) = &self.boot_string {
+            args.push("--boot-string".to_string());
+            args.push(boot_string.clone());
+        }
+
+        // Add debug mode if enabled
+        if self.debug {
+            args.push("--debug".to_string());
+        }
+
+        args
+    }
+}
+
+/// Launches a resolved boot
+pub trait Backend {
+    /// Short name used in messages
+    fn name(&self) -> &'static str;
+
+    /// Describe the process that would boot `boot`, without running it
+    fn plan(&self, boot: &ResolvedBoot) -> Result<BootPlan, String>;
+
+    /// Start the boot; the caller waits for the returned child. With
+    /// `capture`, stdout and stderr are piped so the caller can read them.
+    /// Unless stdin is a terminal, the child leads a new process group, so
+    /// signals sent to the group also reach anything a wrapper script started.
+    fn launch(&self, boot: &ResolvedBoot, capture: bool) -> Result<tokio::process::Child, String> {
+        let plan = self.plan(boot)?;
+        let mut command = tokio::process::Command::from(plan.to_command());
+        if supervisor::use_process_group() {
+            command.process_group(0);
+        }
+        if capture {
+            command.stdout(Stdio::piped()).stderr(Stdio::piped());
+        }
+        command
+            .spawn()
+            .map_err(|e| format!("failed to start {}: {}", plan.program.display(), e))
+    }
+}
+
+/// The cozy-os command-line interface
+pub struct CozyOsBackend;
+
+impl Backend for CozyOsBackend {
+    fn name(&self) -> &'static str {
+        "cozy-os"
+    }
+
+    fn plan(&self, boot: &ResolvedBoot) -> Result<BootPlan, String> {
+        Ok(BootPlan::builder(&boot.executable)
+            .args(boot.cozy_os_args())
+            .build())
+    }
+}
+
+/// Placeholders that are replaced inside a template element
+pub const SCALAR_PLACEHOLDERS: &[&str] = &["executable", "kern_root", "user_root", "boot_string"];
+
+/// Placeholders that must stand alone and expand to zero or more elements
+pub const LIST_PLACEHOLDERS: &[&str] = &["args", "bootargs", "mounts", "bin", "debug"];
+
+/// Runs a command line described in the config, e.g.
+/// `template = ["{executable}", "--headless", "{args}"]`.
+///
+/// `{executable}`, `{kern_root}`, `{user_root}` and `{boot_string}` are
+/// substituted anywhere in an element. `{args}` (the full cozy-os argument
+/// list), `{bootargs}`, `{mounts}`, `{bin}` and `{debug}` must be a whole
+/// element and expand to as many elements as needed.
+pub struct TemplateBackend {
+    pub template: Vec<String>,
+}
+
+impl TemplateBackend {
+    fn scalar(boot: &ResolvedBoot, name: &str) -> Option<String> {
+        match name {
+            "executable" => Some(boot.executable.to_string_lossy().to_string()),
+            "kern_root" => Some(boot.kern_root.clone()),
+            "user_root" => Some(boot.user_root.clone()),
+            "boot_string" => Some(boot.boot_string.clone().unwrap_or_default()),
+            _ => None,
+        }
+    }
+
+    fn list(boot: &ResolvedBoot, name: &str) -> Option<Vec<String>> {
+        match name {
+            "args" => Some(boot.cozy_os_args()),
+            "bootargs" => Some(boot.bootargs.clone()),
+            "mounts" => Some(boot.mount_args()),
+            "bin" => Some(boot.bin_args()),
+            "debug" => Some(if boot.debug {
+                vec!["--debug".to_string()]
+            } else {
+                Vec::new()
+            }),
+            _ => None,
+        }
+    }
+
+    /// Expand the template into argv
+    pub fn render(&self, boot: &ResolvedBoot) -> Result<Vec<String>, String> {
+        let mut argv = Vec::new();
+        for element in &self.template {
+            if let Some(name) = element
+                .strip_prefix('{')
+                .and_then(|rest| rest.strip_suffix('}'))
+            {
+                if let Some(values) = Self::list(boot, name) {
+                    argv.extend(values);
+                    continue;
+                }
+            }
+            argv.push(substitute(element, |name| Self::scalar(boot, name))?);
+        }
+
+        if argv.is_empty() {
+            return Err("runtime.template is empty".to_string());
+        }
+        Ok(argv)
+    }
+}
+
+impl Backend for TemplateBackend {
+    fn name(&self) -> &'static str {
+        "template"
+    }
+
+    fn plan(&self, boot: &ResolvedBoot) -> Result<BootPlan, String> {
+        let argv = self.render(boot)?;
+        Ok(BootPlan::builder(&argv[0]).args(&argv[1..]).build())
+    }
+}
+
+// Replace `{name}` placeholders inside a single template element
+fn substitute(element: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<String, String> {
+    let mut result = String::new();
+    let mut rest = element;
+    while let Some(start) = rest.find('{') {
+        result.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("unterminated '{{' in template element '{}'", element))?;
+        let name = &rest[start + 1..start + end];
+        match lookup(name) {
+            Some(value) => result.push_str(&value),
+            None if LIST_PLACEHOLDERS.contains(&name) => {
+                return Err(format!(
+                    "'{{{}}}' must be a template element on its own",
+                    name
+                ));
+            }
+            None => {
+                return Err(format!(
+                    "unknown placeholder '{{{}}}' in template element '{}'",
+                    name, element
+                ))
+            }
+        }
+        rest = &rest[start + end + 1..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+/// Check a template for unknown or misplaced placeholders without a boot to render
+pub fn check_template(template: &[String]) -> Result<(), String> {
+    if template.is_empty() {
+        return Err("runtime.template is empty".to_string());
+    }
+    for element in template {
+        let whole = element
+            .strip_prefix('{')
+            .and_then(|rest| rest.strip_suffix('}'));
+        if whole.is_some_and(|name| LIST_PLACEHOLDERS.contains(&name)) {
+            continue;
+        }
+        substitute(element, |name| {
+            SCALAR_PLACEHOLDERS.contains(&name).then(String::new)
+        })?;
+    }
+    Ok(())
+}
+
+/// Create the backend selected by `[runtime] backend`
+pub fn select(runtime: &RuntimeConfig) -> Result<Box<dyn Backend>, String> {
+    match runtime.backend {
+        BackendKind::CozyOs => Ok(Box::new(CozyOsBackend)),
+        BackendKind::Template => {
+            let template = runtime
+                .template
+                .clone()
+                .ok_or("runtime.backend = \"template\" needs a runtime.template argv list")?;
+            check_template(&template)?;
+            Ok(Box::new(TemplateBackend { template }))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn boot() -> ResolvedBoot {
+        ResolvedBoot {
+            executable: PathBuf::from("/bin/cozy-os"),
+            kern_root: "/kern".to_string(),
+            user_root: "/user".to_string(),
+            bootargs: vec!["--quiet".to_string()],
+            mounts: vec![ResolvedMount {
+                host_path: "/src".to_string(),
+                guest_path: "/mnt/src".to_string(),
+                readonly: true,
+            }],
+            allow_32bit: Some(false),
+            allow_universal: None,
+            allow_64bit: None,
+            boot_string: Some("single".to_string()),
+            debug: false,
+        }
+    }
+
+    fn template(elements: &[&str]) -> TemplateBackend {
+        TemplateBackend {
+            template: elements.iter().map(|element| element.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn builds_the_cozy_os_command_line() {
+        let plan = CozyOsBackend.plan(&boot()).unwrap();
+        assert_eq!(plan.program, PathBuf::from("/bin/cozy-os"));
+        assert_eq!(
+            plan.args,
+            [
+                "--kern-root",
+                "/kern",
+                "--user-root",
+                "/user",
+                "--quiet",
+                "--mount",
+                "/src:/mnt/src:ro",
+                "--allow-32bit",
+                "false",
+                "--boot-string",
+                "single"
+            ]
+        );
+    }
+
+    #[test]
+    fn renders_templates() {
+        let backend = template(&[
+            "sudo",
+            "{executable}",
+            "--root={kern_root},{user_root}",
+            "{mounts}",
+            "{debug}",
+            "--",
+            "{bootargs}",
+        ]);
+        assert_eq!(
+            backend.render(&boot()).unwrap(),
+            [
+                "sudo",
+                "/bin/cozy-os",
+                "--root=/kern,/user",
+                "--mount",
+                "/src:/mnt/src:ro",
+                "--",
+                "--quiet"
+            ]
+        );
+
+        let plan = template(&["{executable}", "{args}"]).plan(&boot()).unwrap();
+        assert_eq!(plan, CozyOsBackend.plan(&boot()).unwrap());
+    }
+
+    #[test]
+    fn rejects_bad_placeholders() {
+        for (elements, error) in [
+            (
+                &["{executable}", "{nope}"][..],
+                "unknown placeholder '{nope}'",
+            ),
+            (
+                &["x", "--a={args}"][..],
+                "'{args}' must be a template element",
+            ),
+            (&["x", "{kern_root"][..], "unterminated '{'"),
+            (&[][..], "runtime.template is empty"),
+        ] {
+            let template: Vec<String> = elements.iter().map(|e| e.to_string()).collect();
+            let checked = check_template(&template).unwrap_err();
+            let rendered = TemplateBackend { template }.render(&boot()).unwrap_err();
+            assert!(checked.starts_with(error), "{}", checked);
+            assert_eq!(checked, rendered);
+        }
+        assert!(check_template(&["{executable}".to_string(), "{args}".to_string()]).is_ok());
+    }
+
+    #[test]
+    fn selects_the_configured_backend() {
+        let mut runtime = RuntimeConfig::default();
+        assert_eq!(select(&runtime).unwrap().name(), "cozy-os");
+        runtime.backend = BackendKind::Template;
+        let error = select(&runtime).err().unwrap();
+        assert!(error.contains("needs a runtime.template"), "{}", error);
+        runtime.template = Some(vec!["{executable}".to_string(), "{args}".to_string()]);
+        assert_eq!(select(&runtime).unwrap().name(), "template");
+    }
+}
@@ -0,0 +1,514 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use crate::config::BinSettings;
+
+// Enough for an ELF or Mach-O header and a fat header's table of slices
+const HEADER_LEN: usize = 4096;
+
+// Java class files share the fat magic; they never have this few "slices"
+const MAX_FAT_SLICES: u32 = 20;
+
+/// Word size of a binary or one slice of a universal binary
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WordSize {
+    Bits32,
+    Bits64,
+}
+
+impl fmt::Display for WordSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WordSize::Bits32 => write!(f, "32-bit"),
+            WordSize::Bits64 => write!(f, "64-bit"),
+        }
+    }
+}
+
+/// One architecture a binary contains
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Slice {
+    pub word_size: WordSize,
+    pub arch: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Elf,
+    MachO,
+    /// A Mach-O fat binary holding one slice per architecture
+    Universal,
+}
+
+/// An ELF or Mach-O file found by [`scan`]
+#[derive(Debug, Clone)]
+pub struct Binary {
+    /// Relative to the scanned directory
+    pub path: PathBuf,
+    pub format: Format,
+    pub slices: Vec<Slice>,
+}
+
+impl fmt::Display for Binary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.format, self.slices.as_slice()) {
+            (Format::Elf, [slice]) => write!(f, "ELF {} {}", slice.word_size, slice.arch),
+            (Format::MachO, [slice]) => write!(f, "Mach-O {} {}", slice.word_size, slice.arch),
+            (_, slices) => {
+                let archs: Vec<String> = slices
+                    .iter()
+                    .map(|slice| format!("{} {}", slice.word_size, slice.arch))
+                    .collect();
+                write!(f, "Mach-O universal ({})", archs.join(", "))
+            }
+        }
+    }
+}
+
+impl Binary {
+    /// Why the `[bin]` policy would refuse to run this binary, if it would.
+    /// An unset `allow_*` flag leaves the choice to CozyOS, so it allows.
+    pub fn rejection(&self, bin: &BinSettings) -> Option<String> {
+        let allows = |word_size| match word_size {
+            WordSize::Bits32 => bin.allow_32bit != Some(false),
+            WordSize::Bits64 => bin.allow_64bit != Some(false),
+        };
+        let flag = |word_size| match word_size {
+            WordSize::Bits32 => "allow_32bit",
+            WordSize::Bits64 => "allow_64bit",
+        };
+
+        if self.format == Format::Universal {
+            if bin.allow_universal == Some(false) {
+                return Some(
+                    "universal binaries are not allowed (allow_universal = false)".to_string(),
+                );
+            }
+            if !self.slices.iter().any(|slice| allows(slice.word_size)) {
+                return Some("no slice has an allowed word size".to_string());
+            }
+            return None;
+        }
+        self.slices
+            .iter()
+            .find(|slice| !allows(slice.word_size))
+            .map(|slice| {
+                format!(
+                    "{} binaries are not allowed ({} = false)",
+                    slice.word_size,
+                    flag(slice.word_size)
+                )
+            })
+    }
+}
+
+/// Everything [`scan`] found
+#[derive(Debug, Default)]
+pub struct ScanReport {
+    pub binaries: Vec<Binary>,
+    /// Regular files looked at, binaries or not
+    pub files: u64,
+    /// Files and directories that could not be read
+    pub errors: Vec<String>,
+}
+
+/// Find every ELF and Mach-O file under `root`, without following symlinks
+pub fn scan(root: &Path) -> ScanReport {
+    let mut report = ScanReport::default();
+    walk(root, root, &mut report);
+    report.binaries.sort_by(|a, b| a.path.cmp(&b.path));
+    report
+}
+
+fn walk(root: &Path, dir: &Path, report: &mut ScanReport) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            report.errors.push(format!("{}: {}", dir.display(), e));
+            return;
+        }
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let Ok(kind) = entry.file_type() else {
+            continue;
+        };
+        if kind.is_dir() {
+            walk(root, &path, report);
+            continue;
+        }
+        if !kind.is_file() {
+            continue;
+        }
+
+        report.files += 1;
+        match read_header(&path) {
+            Ok(header) => {
+                if let Some((format, slices)) = classify(&header) {
+                    let relative = path.strip_prefix(root).unwrap_or(&path).to_path_buf();
+                    report.binaries.push(Binary {
+                        path: relative,
+                        format,
+                        slices,
+                    });
+                }
+            }
+            Err(e) => report.errors.push(format!("{}: {}", path.display(), e)),
+        }
+    }
+}
+
+fn read_header(path: &Path) -> std::io::Result<Vec<u8>> {
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    File::open(path)?
+        .take(HEADER_LEN as u64)
+        .read_to_end(&mut header)?;
+    Ok(header)
+}
+
+/// Recognise an ELF, Mach-O or fat Mach-O header
+pub fn classify(header: &[u8]) -> Option<(Format, Vec<Slice>)> {
+    let magic = header.get(..4)?;
+    if magic == b"\x7fELF" {
+        return elf(header).map(|slice| (Format::Elf, vec![slice]));
+    }
+
+    let big = u32_at(header, 0, true)?;
+    match big {
+        // Thin Mach-O, stored in either byte order
+        0xfeedface | 0xfeedfacf => macho(header, true).map(|slice| (Format::MachO, vec![slice])),
+        0xcefaedfe | 0xcffaedfe => macho(header, false).map(|slice| (Format::MachO, vec![slice])),
+        // Fat headers are always big-endian; 0xcafebabf has 64-bit offsets
+        0xcafebabe | 0xcafebabf => {
+            let count = u32_at(header, 4, true)?;
+            if count == 0 || count >= MAX_FAT_SLICES {
+                return None;
+            }
+            let entry_len = if big == 0xcafebabf { 32 } else { 20 };
+            let slices = (0..count as usize)
+                .map(|index| {
+                    let cpu_type = u32_at(header, 8 + index * entry_len, true)?;
+                    Some(macho_slice(cpu_type))
+                })
+                .collect::<Option<Vec<Slice>>>()?;
+            Some((Format::Universal, slices))
+        }
+        _ => None,
+    }
+}
+
+fn elf(header: &[u8]) -> Option<Slice> {
+    let word_size = match header.get(4)? {
+        1 => WordSize::Bits32,
+        2 => WordSize::Bits64,
+        _ => return None,
+    };
+    let big_endian = match header.get(5)? {
+        1 => false,
+        2 => true,
+        _ => return None,
+    };
+    let machine = u16_at(header, 18, big_endian)?;
+    let arch = match machine {
+        0x03 => "i386",
+        0x08 => "mips",
+        0x14 => "powerpc",
+        0x15 => "powerpc64",
+        0x28 => "arm",
+        0x3e => "x86_64",
+        0xb7 => "aarch64",
+        0xf3 => "riscv",
+        0x102 => "loongarch",
+        other => {
+            return Some(Slice {
+                word_size,
+                arch: format!("machine {:#x}", other),
+            })
+        }
+    };
+    Some(Slice {
+        word_size,
+        arch: arch.to_string(),
+    })
+}
+
+fn macho(header: &[u8], big_endian: bool) -> Option<Slice> {
+    u32_at(header, 4, big_endian).map(macho_slice)
+}
+
+// The CPU type encodes the word size in its ABI64 / ABI64_32 bits
+fn macho_slice(cpu_type: u32) -> Slice {
+    const ABI64: u32 = 0x0100_0000;
+    const ABI64_32: u32 = 0x0200_0000;
+    let word_size = if cpu_type & ABI64 != 0 {
+        WordSize::Bits64
+    } else {
+        WordSize::Bits32
+    };
+    let arch = match cpu_type {
+        7 => "i386",
+        0x0100_0007 => "x86_64",
+        12 => "arm",
+        0x0100_000c => "arm64",
+        0x0200_000c => "arm64_32",
+        18 => "ppc",
+        0x0100_0012 => "ppc64",
+        other => {
+            let base = other & !(ABI64 | ABI64_32);
+            return Slice {
+                word_size,
+                arch: format!("cpu type {}", base),
+            };
+        }
+    };
+    Slice {
+        word_size,
+        arch: arch.to_string(),
+    }
+}
+
+fn u16_at(bytes: &[u8], offset: usize, big_endian: bool) -> Option<u16> {
+    let raw: [u8; 2] = bytes.get(offset..offset + 2)?.try_into().ok()?;
+    Some(if big_endian {
+        u16::from_be_bytes(raw)
+    } else {
+        u16::from_le_bytes(raw)
+    })
+}
+
+fn u32_at(bytes: &[u8], offset: usize, big_endian: bool) -> Option<u32> {
+    let raw: [u8; 4] = bytes.get(offset..offset + 4)?.try_into().ok()?;
+    Some(if big_endian {
+        u32::from_be_bytes(raw)
+    } else {
+        u32::from_le_bytes(raw)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    fn elf_header(class: u8, data: u8, machine: u16) -> Vec<u8> {
+        let mut header = vec![0u8; 64];
+        header[..4].copy_from_slice(b"\x7fELF");
+        header[4] = class;
+        header[5] = data;
+        let machine = if data == 2 {
+            machine.to_be_bytes()
+        } else {
+            machine.to_le_bytes()
+        };
+        header[18..20].copy_from_slice(&machine);
+        header
+    }
+
+    fn fat_header(magic: u32, cpu_types: &[u32]) -> Vec<u8> {
+        let entry_len = if magic == 0xcafebabf { 32 } else { 20 };
+        let mut header = [magic.to_be_bytes(), (cpu_types.len() as u32).to_be_bytes()].concat();
+        for cpu_type in cpu_types {
+            let mut entry = vec![0u8; entry_len];
+            entry[..4].copy_from_slice(&cpu_type.to_be_bytes());
+            header.extend(entry);
+        }
+        header
+    }
+
+    fn slice(word_size: WordSize, arch: &str) -> Slice {
+        Slice {
+            word_size,
+            arch: arch.to_string(),
+        }
+    }
+
+    fn binary(format: Format, slices: Vec<Slice>) -> Binary {
+        Binary {
+            path: PathBuf::from("bin/x"),
+            format,
+            slices,
+        }
+    }
+
+    fn bin(
+        allow_32bit: Option<bool>,
+        allow_universal: Option<bool>,
+        allow_64bit: Option<bool>,
+    ) -> BinSettings {
+        BinSettings {
+            allow_32bit,
+            allow_universal,
+            allow_64bit,
+            scan_on_boot: false,
+        }
+    }
+
+    #[test]
+    fn classifies_elf() {
+        assert_eq!(
+            classify(&elf_header(2, 1, 0x3e)),
+            Some((Format::Elf, vec![slice(WordSize::Bits64, "x86_64")]))
+        );
+        assert_eq!(
+            classify(&elf_header(1, 1, 0x03)),
+            Some((Format::Elf, vec![slice(WordSize::Bits32, "i386")]))
+        );
+        assert_eq!(
+            classify(&elf_header(1, 2, 0x14)),
+            Some((Format::Elf, vec![slice(WordSize::Bits32, "powerpc")]))
+        );
+        assert_eq!(
+            classify(&elf_header(2, 1, 0x1234)),
+            Some((Format::Elf, vec![slice(WordSize::Bits64, "machine 0x1234")]))
+        );
+        assert_eq!(classify(&elf_header(3, 1, 0x3e)), None);
+        assert_eq!(classify(&elf_header(2, 1, 0x3e)[..10]), None);
+    }
+
+    #[test]
+    fn classifies_thin_macho_in_either_byte_order() {
+        let big = [0xfeedfacfu32.to_be_bytes(), 0x0100_000cu32.to_be_bytes()].concat();
+        assert_eq!(
+            classify(&big),
+            Some((Format::MachO, vec![slice(WordSize::Bits64, "arm64")]))
+        );
+        let little = [0xfeedfaceu32.to_le_bytes(), 7u32.to_le_bytes()].concat();
+        assert_eq!(
+            classify(&little),
+            Some((Format::MachO, vec![slice(WordSize::Bits32, "i386")]))
+        );
+        let arm64_32 = [0xfeedfaceu32.to_le_bytes(), 0x0200_000cu32.to_le_bytes()].concat();
+        assert_eq!(
+            classify(&arm64_32),
+            Some((Format::MachO, vec![slice(WordSize::Bits32, "arm64_32")]))
+        );
+    }
+
+    #[test]
+    fn classifies_universal_binaries() {
+        let expected = vec![
+            slice(WordSize::Bits64, "x86_64"),
+            slice(WordSize::Bits64, "arm64"),
+        ];
+        assert_eq!(
+            classify(&fat_header(0xcafebabe, &[0x0100_0007, 0x0100_000c])),
+            Some((Format::Universal, expected.clone()))
+        );
+        assert_eq!(
+            classify(&fat_header(0xcafebabf, &[0x0100_0007, 0x0100_000c])),
+            Some((Format::Universal, expected))
+        );
+        // A slice table cut short by the end of the header
+        assert_eq!(classify(&fat_header(0xcafebabe, &[7, 12])[..30]), None);
+    }
+
+    #[test]
+    fn java_classes_are_not_universal_binaries() {
+        // Magic followed by minor version 0, major version 52 (Java 8)
+        let class = [0xcafebabeu32.to_be_bytes(), 52u32.to_be_bytes()].concat();
+        assert_eq!(classify(&class), None);
+        assert_eq!(classify(&fat_header(0xcafebabe, &[])), None);
+    }
+
+    #[test]
+    fn ignores_other_files() {
+        assert_eq!(classify(b"#!/bin/sh\necho hi\n"), None);
+        assert_eq!(classify(b"\x7fEL"), None);
+        assert_eq!(classify(b""), None);
+    }
+
+    #[test]
+    fn unset_flags_allow() {
+        let unset = bin(None, None, None);
+        assert_eq!(
+            binary(Format::Elf, vec![slice(WordSize::Bits32, "i386")]).rejection(&unset),
+            None
+        );
+        assert_eq!(
+            binary(Format::Universal, vec![slice(WordSize::Bits32, "i386")]).rejection(&unset),
+            None
+        );
+    }
+
+    #[test]
+    fn rejects_thin_binaries_by_word_size() {
+        let elf32 = binary(Format::Elf, vec![slice(WordSize::Bits32, "i386")]);
+        assert_eq!(
+            elf32
+                .rejection(&bin(Some(false), None, Some(true)))
+                .as_deref(),
+            Some("32-bit binaries are not allowed (allow_32bit = false)")
+        );
+        assert_eq!(elf32.rejection(&bin(Some(true), None, Some(false))), None);
+        let macho64 = binary(Format::MachO, vec![slice(WordSize::Bits64, "arm64")]);
+        assert_eq!(
+            macho64.rejection(&bin(None, None, Some(false))).as_deref(),
+            Some("64-bit binaries are not allowed (allow_64bit = false)")
+        );
+    }
+
+    #[test]
+    fn universal_binaries_need_one_allowed_slice() {
+        let fat = binary(
+            Format::Universal,
+            vec![
+                slice(WordSize::Bits32, "i386"),
+                slice(WordSize::Bits64, "x86_64"),
+            ],
+        );
+        assert_eq!(
+            fat.rejection(&bin(Some(false), Some(true), Some(true))),
+            None
+        );
+        assert_eq!(
+            fat.rejection(&bin(Some(false), Some(true), Some(false)))
+                .as_deref(),
+            Some("no slice has an allowed word size")
+        );
+        assert_eq!(
+            fat.rejection(&bin(None, Some(false), None)).as_deref(),
+            Some("universal binaries are not allowed (allow_universal = false)")
+        );
+    }
+
+    #[test]
+    fn describes_binaries() {
+        assert_eq!(
+            binary(Format::Elf, vec![slice(WordSize::Bits64, "x86_64")]).to_string(),
+            "ELF 64-bit x86_64"
+        );
+        let fat = binary(
+            Format::Universal,
+            vec![
+                slice(WordSize::Bits32, "i386"),
+                slice(WordSize::Bits64, "x86_64"),
+            ],
+        );
+        assert_eq!(
+            fat.to_string(),
+            "Mach-O universal (32-bit i386, 64-bit x86_64)"
+        );
+    }
+
+    #[test]
+    fn scans_nested_directories() {
+        let root = TempDir::new("binscan");
+        fs::create_dir_all(root.join("usr/bin")).unwrap();
+        fs::write(root.join("usr/bin/tool"), elf_header(2, 1, 0xb7)).unwrap();
+        fs::write(root.join("boot"), elf_header(1, 1, 0x28)).unwrap();
+        fs::write(root.join("readme"), "not a binary").unwrap();
+
+        let report = scan(root.path());
+        let paths: Vec<&Path> = report
+            .binaries
+            .iter()
+            .map(|binary| binary.path.as_path())
+            .collect();
+        assert_eq!(paths, [Path::new("boot"), Path::new("usr/bin/tool")]);
+        assert_eq!(report.files, 3);
+        assert!(report.errors.is_empty());
+    }
+}
@@ -0,0 +1,221 @@
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use toml::Value;
+
+/// A single `[bootargs]` entry.
+///
+/// Either a plain TOML value (`console = "ttyS0"`, `smp = 4`, `quiet = true`)
+/// or a table that also controls how it is emitted:
+/// `console = { value = "ttyS0", priority = -10 }`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum BootArg {
+    Detailed(BootArgSpec),
+    Plain(Value),
+}
+
+/// The table form of a boot argument. Only tables made up of exactly these
+/// keys are read this way; any other table is treated as nested arguments.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BootArgSpec {
+    pub value: Value,
+    /// Lower priorities are emitted first; the default is 0
+    #[serde(default)]
+    pub priority: i64,
+    /// Join array values with this separator instead of repeating the flag
+    pub join: Option<String>,
+}
+
+impl BootArg {
+    pub fn value(&self) -> &Value {
+        match self {
+            BootArg::Plain(value) => value,
+            BootArg::Detailed(spec) => &spec.value,
+        }
+    }
+
+    pub fn priority(&self) -> i64 {
+        match self {
+            BootArg::Plain(_) => 0,
+            BootArg::Detailed(spec) => spec.priority,
+        }
+    }
+
+    fn join(&self) -> Option<&str> {
+        match self {
+            BootArg::Plain(_) => None,
+            BootArg::Detailed(spec) => spec.join.as_deref(),
+        }
+    }
+
+    /// Render this argument as command-line flags.
+    ///
+    /// - strings, integers and floats become `--key=value`
+    /// - `true` becomes `--key`, `false` becomes `--no-key`
+    /// - arrays repeat the flag per element, or are joined when `join` is set
+    /// - tables become dotted keys, e.g. `--net.ip=10.0.0.2`
+    ///
+    /// String values are passed through `expand` first.
+    pub fn render<E>(
+        &self,
+        key: &str,
+        expand: &impl Fn(&str) -> Result<String, E>,
+    ) -> Result<Vec<String>, E> {
+        let mut flags = Vec::new();
+        render_value(key, self.value(), self.join(), expand, &mut flags)?;
+        Ok(flags)
+    }
+}
+
+fn render_value<E>(
+    key: &str,
+    value: &Value,
+    join: Option<&str>,
+    expand: &impl Fn(&str) -> Result<String, E>,
+    flags: &mut Vec<String>,
+) -> Result<(), E> {
+    match value {
+        Value::Boolean(true) => flags.push(format!("--{}", key)),
+        Value::Boolean(false) => flags.push(format!("--no-{}", key)),
+        Value::Array(items) => match join {
+            Some(separator) => {
+                let parts = items
+                    .iter()
+                    .map(|item| scalar_to_string(item, expand))
+                    .collect::<Result<Vec<_>, E>>()?;
+                flags.push(format!("--{}={}", key, parts.join(separator)));
+            }
+            None => {
+                for item in items {
+                    render_value(key, item, None, expand, flags)?;
+                }
+            }
+        },
+        Value::Table(table) => {
+            for (child, item) in table {
+                render_value(&format!("{}.{}", key, child), item, join, expand, flags)?;
+            }
+        }
+        scalar => flags.push(format!("--{}={}", key, scalar_to_string(scalar, expand)?)),
+    }
+    Ok(())
+}
+
+// Format a value that appears on the right-hand side of `--key=`
+fn scalar_to_string<E>(
+    value: &Value,
+    expand: &impl Fn(&str) -> Result<String, E>,
+) -> Result<String, E> {
+    Ok(match value {
+        Value::String(s) => expand(s)?,
+        Value::Integer(i) => i.to_string(),
+        Value::Float(f) => f.to_string(),
+        Value::Boolean(b) => b.to_string(),
+        Value::Datetime(dt) => dt.to_string(),
+        // Nested containers inside a joined list have no flat form; fall back to TOML
+        other => other.to_string(),
+    })
+}
+
+/// Boot arguments in the order they were written in the config
+pub type BootArgs = IndexMap<String, BootArg>;
+
+/// Return the boot arguments in command-line order: by priority, keeping file
+/// order between entries with the same priority.
+pub fn ordered(bootargs: &BootArgs) -> Vec<(&str, &BootArg)> {
+    let mut ordered: Vec<(&str, &BootArg)> = bootargs
+        .iter()
+        .map(|(key, arg)| (key.as_str(), arg))
+        .collect();
+    ordered.sort_by_key(|(_, arg)| arg.priority());
+    ordered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bootargs(text: &str) -> BootArgs {
+        toml::from_str(text).unwrap()
+    }
+
+    fn render(text: &str) -> Vec<String> {
+        let args = bootargs(text);
+        let expand = |s: &str| Ok::<_, String>(s.replace("$(devroot)", "/dev/root"));
+        ordered(&args)
+            .into_iter()
+            .flat_map(|(key, arg)| arg.render(key, &expand).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn renders_scalars_by_type() {
+        assert_eq!(
+            render("console = \"ttyS0\"\nsmp = 4\nscale = 1.5\nquiet = true\nsplash = false\n"),
+            [
+                "--console=ttyS0",
+                "--smp=4",
+                "--scale=1.5",
+                "--quiet",
+                "--no-splash"
+            ]
+        );
+    }
+
+    #[test]
+    fn expands_strings() {
+        assert_eq!(
+            render("root = \"$(devroot)/disk\"\nmods = [\"$(devroot)/a\"]\n"),
+            ["--root=/dev/root/disk", "--mods=/dev/root/a"]
+        );
+        let args = bootargs("root = \"$(x)\"\n");
+        let failed = args["root"].render("root", &|_: &str| Err::<String, _>("undefined"));
+        assert_eq!(failed, Err("undefined"));
+    }
+
+    #[test]
+    fn arrays_repeat_or_join() {
+        assert_eq!(
+            render("mod = [\"fs\", \"net\"]\n"),
+            ["--mod=fs", "--mod=net"]
+        );
+        assert_eq!(
+            render("modules = { value = [\"fs\", \"net\", 3], join = \",\" }\n"),
+            ["--modules=fs,net,3"]
+        );
+        assert_eq!(render("flag = [true, false]\n"), ["--flag", "--no-flag"]);
+        assert!(render("none = []\n").is_empty());
+    }
+
+    #[test]
+    fn tables_become_dotted_keys() {
+        assert_eq!(
+            render("[net]\nip = \"10.0.0.2\"\ndhcp = false\n[net.dns]\nservers = [\"a\", \"b\"]\n"),
+            [
+                "--net.ip=10.0.0.2",
+                "--no-net.dhcp",
+                "--net.dns.servers=a",
+                "--net.dns.servers=b"
+            ]
+        );
+    }
+
+    #[test]
+    fn only_exact_spec_tables_are_detailed() {
+        let args = bootargs("a = { value = 1, priority = 2 }\nb = { value = 1, label = \"x\" }\n");
+        assert!(matches!(args["a"], BootArg::Detailed(_)));
+        assert!(matches!(args["b"], BootArg::Plain(_)));
+        assert_eq!(
+            render("b = { value = 1, label = \"x\" }\n"),
+            ["--b.value=1", "--b.label=x"]
+        );
+    }
+
+    #[test]
+    fn orders_by_priority_then_file_order() {
+        let args = bootargs("smp = 4\nconsole = { value = \"ttyS0\", priority = -10 }\nquiet = true\nlast = { value = 1, priority = 5 }\nnull = \"0\"\n");
+        let keys: Vec<&str> = ordered(&args).into_iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, ["console", "smp", "quiet", "null", "last"]);
+    }
+}
@@ -0,0 +1,327 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+
+use crate::digest;
+use crate::duration::HumanDuration;
+use crate::vars::Variables;
+
+/// How `boot` decides the kernel output is out of date
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum StaleCheck {
+    /// An input modified after the output
+    #[default]
+    Mtime,
+    /// Input contents differ from the last successful build
+    Hash,
+}
+
+/// The `[build]` section
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BuildConfig {
+    /// Shell command that builds the kernel; `$(...)` references are expanded
+    pub command: String,
+    /// Glob patterns for the files the build reads; `*`, `?`, `[...]` and `**`
+    /// are supported. Relative patterns start from the config file's directory.
+    #[serde(default)]
+    pub inputs: Vec<String>,
+    /// File the build produces, relative to `kern_root`
+    pub output: String,
+    #[serde(default)]
+    pub check: StaleCheck,
+    pub timeout: Option<HumanDuration>,
+}
+
+impl BuildConfig {
+    /// Expand the command and inputs and locate the output under `kern_root`
+    pub fn resolve(&self, vars: &Variables, kern_root: &str) -> Result<BuildPlan, String> {
+        let command = vars
+            .expand(&self.command)
+            .map_err(|e| format!("failed to expand build.command: {}", e))?;
+
+        let mut inputs = Vec::new();
+        for pattern in &self.inputs {
+            let expanded = vars
+                .expand(pattern)
+                .map_err(|e| format!("failed to expand build input '{}': {}", pattern, e))?;
+            inputs.extend(
+                glob(&expanded, vars.config_dir())
+                    .map_err(|e| format!("invalid build input '{}': {}", pattern, e))?,
+            );
+        }
+        inputs.sort();
+        inputs.dedup();
+
+        let output = vars
+            .expand(&self.output)
+            .map_err(|e| format!("failed to expand build.output: {}", e))?;
+        if Path::new(&output)
+            .components()
+            .any(|component| component == Component::ParentDir)
+        {
+            return Err(format!(
+                "build.output '{}' must stay inside kern_root",
+                output
+            ));
+        }
+
+        Ok(BuildPlan {
+            command,
+            inputs,
+            output: Path::new(kern_root).join(output),
+            check: self.check,
+            timeout: self.timeout.map(HumanDuration::as_duration),
+        })
+    }
+}
+
+/// A `[build]` section with everything expanded
+#[derive(Debug, Clone)]
+pub struct BuildPlan {
+    pub command: String,
+    /// Input files matched by the globs
+    pub inputs: Vec<PathBuf>,
+    pub output: PathBuf,
+    pub check: StaleCheck,
+    pub timeout: Option<Duration>,
+}
+
+/// Why the kernel does or does not need rebuilding
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Staleness {
+    Fresh,
+    MissingOutput,
+    /// This input was modified after the output
+    NewerInput(PathBuf),
+    /// Input contents differ from the last recorded build
+    ChangedInputs,
+}
+
+impl Staleness {
+    pub fn needs_build(&self) -> bool {
+        *self != Staleness::Fresh
+    }
+}
+
+impl fmt::Display for Staleness {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Staleness::Fresh => write!(f, "up to date"),
+            Staleness::MissingOutput => write!(f, "output does not exist"),
+            Staleness::NewerInput(path) => write!(f, "{} is newer than the output", path.display()),
+            Staleness::ChangedInputs => write!(f, "inputs changed since the last build"),
+        }
+    }
+}
+
+impl BuildPlan {
+    pub fn staleness(&self) -> Result<Staleness, String> {
+        let Ok(output_modified) =
+            fs::metadata(&self.output).and_then(|metadata| metadata.modified())
+        else {
+            return Ok(Staleness::MissingOutput);
+        };
+
+        match self.check {
+            StaleCheck::Mtime => {
+                for input in &self.inputs {
+                    let modified = fs::metadata(input)
+                        .and_then(|metadata| metadata.modified())
+                        .map_err(|e| format!("failed to read {}: {}", input.display(), e))?;
+                    if modified > output_modified {
+                        return Ok(Staleness::NewerInput(input.clone()));
+                    }
+                }
+                Ok(Staleness::Fresh)
+            }
+            StaleCheck::Hash => {
+                let recorded = self
+                    .stamp_path()
+                    .and_then(|stamp| fs::read_to_string(stamp).ok());
+                if recorded.as_deref().map(str::trim) == Some(self.inputs_hash()?.as_str()) {
+                    Ok(Staleness::Fresh)
+                } else {
+                    Ok(Staleness::ChangedInputs)
+                }
+            }
+        }
+    }
+
+    /// Remember the inputs of a successful build for the hash check. Failing
+    /// to does not undo the build; the next hash check just finds it stale.
+    pub fn record(&self) -> Result<(), String> {
+        if self.check != StaleCheck::Hash {
+            return Ok(());
+        }
+        let stamp = self
+            .stamp_path()
+            .ok_or("no cache directory to record the build inputs in")?;
+        if let Some(parent) = stamp.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|e| format!("failed to create {}: {}", parent.display(), e))?;
+        }
+        fs::write(&stamp, self.inputs_hash()?)
+            .map_err(|e| format!("failed to write {}: {}", stamp.display(), e))
+    }
+
+    // One digest over the command and every input's path and contents
+    fn inputs_hash(&self) -> Result<String, String> {
+        let mut hasher = Sha256::new();
+        hasher.update(self.command.as_bytes());
+        for input in &self.inputs {
+            let file_hash = digest::sha256_file(input)
+                .map_err(|e| format!("failed to read {}: {}", input.display(), e))?;
+            hasher.update(b"\0");
+            hasher.update(input.to_string_lossy().as_bytes());
+            hasher.update(b"\0");
+            hasher.update(file_hash.as_bytes());
+        }
+        Ok(digest::hex(&hasher.finalize()))
+    }
+
+    // Kept in the user cache so nothing extra appears in kern_root
+    fn stamp_path(&self) -> Option<PathBuf> {
+        let mut key = Sha256::new();
+        key.update(self.output.to_string_lossy().as_bytes());
+        dirs::cache_dir().map(|cache| {
+            cache
+                .join("cozyboot")
+                .join("build")
+                .join(digest::hex(&key.finalize()))
+        })
+    }
+}
+
+/// Files matching `pattern`, sorted. `*` and `?` match within one path
+/// component, `**` matches any number of directories, and wildcards skip
+/// hidden files like shells do. A relative pattern is resolved against `base`.
+pub fn glob(pattern: &str, base: &Path) -> Result<Vec<PathBuf>, String> {
+    // `dir/**` means every file below dir; on its own `**` only matches directories
+    let pattern = match pattern.strip_suffix("**") {
+        Some(dir) if dir.is_empty() || dir.ends_with('/') => format!("{}/*", pattern),
+        _ => pattern.to_string(),
+    };
+    let pattern = if Path::new(&pattern).is_absolute() {
+        pattern
+    } else {
+        // The base is a literal path, even if it contains `*` or `[`
+        Path::new(&glob::Pattern::escape(&base.to_string_lossy()))
+            .join(pattern)
+            .to_string_lossy()
+            .to_string()
+    };
+    let options = glob::MatchOptions {
+        case_sensitive: true,
+        require_literal_separator: true,
+        require_literal_leading_dot: true,
+    };
+    let paths = glob::glob_with(&pattern, options).map_err(|e| e.to_string())?;
+    let mut matches: Vec<PathBuf> = paths.flatten().filter(|path| path.is_file()).collect();
+    matches.sort();
+    matches.dedup();
+    Ok(matches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    fn names(dir: &Path, matches: Vec<PathBuf>) -> Vec<String> {
+        matches
+            .iter()
+            .map(|path| {
+                path.strip_prefix(dir)
+                    .unwrap()
+                    .to_string_lossy()
+                    .to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn glob_wildcards() {
+        let tmp = TempDir::new("glob").with_files(&[
+            "src/main.rs",
+            "src/lib.rs",
+            "src/arch/x86.rs",
+            "src/.hidden.rs",
+            "src/notes.txt",
+            "Makefile",
+        ]);
+        let dir = tmp.path();
+        let glob = |pattern: &str| names(dir, glob(pattern, dir).unwrap());
+        assert_eq!(glob("src/*.rs"), ["src/lib.rs", "src/main.rs"]);
+        assert_eq!(
+            glob("src/**/*.rs"),
+            ["src/arch/x86.rs", "src/lib.rs", "src/main.rs"]
+        );
+        assert_eq!(glob("src/ma?n.rs"), ["src/main.rs"]);
+        assert_eq!(glob("src/[lm]*.rs"), ["src/lib.rs", "src/main.rs"]);
+        assert_eq!(glob("Makefile"), ["Makefile"]);
+        assert!(glob("src/*.c").is_empty());
+    }
+
+    #[test]
+    fn glob_directory_contents() {
+        let tmp = TempDir::new("glob-dir").with_files(&["src/main.rs", "src/arch/x86.rs"]);
+        let dir = tmp.path();
+        assert_eq!(
+            names(dir, glob("src/**", dir).unwrap()),
+            ["src/arch/x86.rs", "src/main.rs"]
+        );
+    }
+
+    #[test]
+    fn glob_absolute_patterns_ignore_the_base() {
+        let tmp = TempDir::new("glob-abs").with_files(&["a.rs"]);
+        let dir = tmp.path();
+        let pattern = format!("{}/*.rs", dir.display());
+        assert_eq!(
+            names(dir, glob(&pattern, Path::new("/nonexistent")).unwrap()),
+            ["a.rs"]
+        );
+    }
+
+    #[test]
+    fn glob_rejects_invalid_patterns() {
+        assert!(glob("src/***/x", Path::new(".")).is_err());
+    }
+
+    #[test]
+    fn mtime_staleness() {
+        let tmp = TempDir::new("stale").with_files(&["in.c", "kern/kernel.img"]);
+        let dir = tmp.path();
+        let plan = BuildPlan {
+            command: "make".to_string(),
+            inputs: vec![dir.join("in.c")],
+            output: dir.join("kern/kernel.img"),
+            check: StaleCheck::Mtime,
+            timeout: None,
+        };
+        let old = std::time::SystemTime::now() - Duration::from_secs(60);
+        fs::File::options()
+            .write(true)
+            .open(dir.join("kern/kernel.img"))
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+        assert_eq!(
+            plan.staleness().unwrap(),
+            Staleness::NewerInput(dir.join("in.c"))
+        );
+        fs::File::options()
+            .write(true)
+            .open(dir.join("in.c"))
+            .unwrap()
+            .set_modified(old - Duration::from_secs(60))
+            .unwrap();
+        assert_eq!(plan.staleness().unwrap(), Staleness::Fresh);
+        fs::remove_file(dir.join("kern/kernel.img")).unwrap();
+        assert_eq!(plan.staleness().unwrap(), Staleness::MissingOutput);
+    }
+}
@@ -0,0 +1,365 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::bootargs::BootArgs;
+use crate::build::BuildConfig;
+use crate::diagnostics::Diagnostic;
+use crate::expect::ExpectRule;
+use crate::hooks::HooksConfig;
+use crate::layers::{self, Layer, MergedConfig};
+use crate::logging::LoggingConfig;
+use crate::manifest::RequiredFile;
+use crate::profiles;
+use crate::runtime::RuntimeConfig;
+use crate::supervisor::SupervisorConfig;
+use crate::vars::{expand_variables, Variables};
+
+/// Every config layer, merged and with the selected profile applied
+#[derive(Debug)]
+pub struct LoadedConfig {
+    pub layers: Vec<Layer>,
+    pub merged: MergedConfig,
+    pub profile: Option<String>,
+}
+
+impl LoadedConfig {
+    /// Read all config layers and apply the requested (or default) profile
+    pub fn load(explicit: Option<&Path>, profile: Option<&str>) -> Result<Self, Diagnostic> {
+        let layers = layers::discover(explicit)?;
+        let mut merged = layers::merge(&layers);
+
+        let selected = profiles::select_profile(&mut merged.table, profile)?;
+        let profile = selected.map(|selected| {
+            for (name, overlay) in selected.chain {
+                merged.apply_profile(&name, overlay);
+            }
+            selected.name
+        });
+
+        Ok(LoadedConfig {
+            layers,
+            merged,
+            profile,
+        })
+    }
+
+    /// The most specific config file that was read, used for $(config_dir)
+    pub fn config_path(&self) -> PathBuf {
+        self.layers
+            .iter()
+            .rev()
+            .find_map(|layer| layer.path.clone())
+            .unwrap_or_else(|| PathBuf::from("."))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CozyBootConfig {
+    pub main: MainConfig,
+    pub bootargs: BootArgs,
+    pub bin: BinSettings,
+    #[serde(default)]
+    pub mounts: Vec<MountPoint>,
+    #[serde(default)]
+    pub vars: HashMap<String, String>,
+    #[serde(default)]
+    pub runtime: RuntimeConfig,
+    #[serde(default)]
+    pub supervisor: SupervisorConfig,
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    #[serde(default)]
+    pub expect: Vec<ExpectRule>,
+    #[serde(default)]
+    pub hooks: HooksConfig,
+    pub build: Option<BuildConfig>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MainConfig {
+    pub kern_root: String,
+    pub user_root: String,
+    /// Files that must be in `kern_root` before booting, besides those in its manifest.toml
+    #[serde(default)]
+    pub required_files: Vec<RequiredFile>,
+    /// Directories every user root must contain, relative to `user_root`
+    #[serde(default)]
+    pub user_skeleton: Vec<String>,
+    /// Directory copied to create `user_root` when it does not exist
+    pub user_root_template: Option<String>,
+    /// Where `boot snapshot` keeps copies of `user_root` (default: `<user_root>.snapshots`)
+    pub snapshot_dir: Option<String>,
+    /// Snapshot `user_root` before every boot
+    #[serde(default)]
+    pub snapshot_on_boot: bool,
+    /// Automatic snapshots kept; older ones are deleted
+    #[serde(default = "default_snapshot_keep")]
+    pub snapshot_keep: usize,
+}
+
+fn default_snapshot_keep() -> usize {
+    5
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BinSettings {
+    pub allow_32bit: Option<bool>,
+    pub allow_universal: Option<bool>,
+    pub allow_64bit: Option<bool>,
+    /// Refuse to boot when user_root holds a binary these flags reject
+    #[serde(default)]
+    pub scan_on_boot: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MountPoint {
+    pub host_path: String,
+    pub guest_path: String,
+    pub readonly: Option<bool>,
+}
+
+impl MountPoint {
+    // Mounts are read-only unless explicitly marked writable
+    pub fn is_readonly(&self) -> bool {
+        self.readonly.unwrap_or(true)
+    }
+}
+
+// A mount entry after variable expansion and validation
+#[derive(Debug, Clone)]
+pub struct ResolvedMount {
+    pub host_path: String,
+    pub guest_path: String,
+    pub readonly: bool,
+}
+
+impl ResolvedMount {
+    // Render as the value of a `--mount` argument: host:guest:ro|rw.
+    // resolve_mounts rejects paths containing ':', so this splits one way only.
+    pub fn to_arg(&self) -> String {
+        let mode = if self.readonly { "ro" } else { "rw" };
+        format!("{}:{}:{}", self.host_path, self.guest_path, mode)
+    }
+}
+
+// Normalize a guest path so overlap checks compare whole components
+fn normalize_guest_path(path: &str) -> Result<PathBuf, String> {
+    if path.contains(':') {
+        return Err(format!(
+            "guest path '{}' contains ':', which separates the parts of --mount",
+            path
+        ));
+    }
+    let path = Path::new(path);
+    if !path.is_absolute() {
+        return Err(format!("guest path '{}' must be absolute", path.display()));
+    }
+
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            std::path::Component::CurDir => {}
+            std::path::Component::ParentDir => {
+                return Err(format!(
+                    "guest path '{}' must not contain '..'",
+                    path.display()
+                ));
+            }
+            other => normalized.push(other),
+        }
+    }
+    Ok(normalized)
+}
+
+/// A problem with one `[[mounts]]` entry
+#[derive(Debug)]
+pub struct MountError {
+    /// Position of the entry in the `mounts` array
+    pub index: usize,
+    /// The field the problem is about
+    pub field: &'static str,
+    pub message: String,
+}
+
+// Validate the [[mounts]] section and expand host paths, collecting every problem
+pub fn resolve_mounts(
+    mounts: &[MountPoint],
+    vars: &Variables,
+) -> Result<Vec<ResolvedMount>, Vec<MountError>> {
+    let mut resolved: Vec<ResolvedMount> = Vec::new();
+    let mut errors: Vec<MountError> = Vec::new();
+    let mut guest_paths: Vec<PathBuf> = Vec::new();
+
+    for (index, mount) in mounts.iter().enumerate() {
+        let host_path = match expand_variables(&mount.host_path, vars) {
+            Ok(host_path) if host_path.contains(':') => {
+                errors.push(MountError {
+                    index,
+                    field: "host_path",
+                    message: format!(
+                        "mount host path '{}' contains ':', which separates the parts of --mount",
+                        host_path
+                    ),
+                });
+                None
+            }
+            Ok(host_path) if Path::new(&host_path).exists() => Some(host_path),
+            Ok(host_path) => {
+                errors.push(MountError {
+                    index,
+                    field: "host_path",
+                    message: format!(
+                        "mount host path '{}' does not exist (expanded from '{}')",
+                        host_path, mount.host_path
+                    ),
+                });
+                None
+            }
+            Err(e) => {
+                errors.push(MountError {
+                    index,
+                    field: "host_path",
+                    message: format!("mount host path '{}': {}", mount.host_path, e),
+                });
+                None
+            }
+        };
+
+        let guest_path = match normalize_guest_path(&mount.guest_path) {
+            Ok(guest_path) => guest_path,
+            Err(message) => {
+                errors.push(MountError {
+                    index,
+                    field: "guest_path",
+                    message,
+                });
+                continue;
+            }
+        };
+        if let Some(existing) = guest_paths
+            .iter()
+            .find(|existing| existing.starts_with(&guest_path) || guest_path.starts_with(existing))
+        {
+            errors.push(MountError {
+                index,
+                field: "guest_path",
+                message: format!(
+                    "mount guest path '{}' overlaps with '{}'",
+                    guest_path.display(),
+                    existing.display()
+                ),
+            });
+            continue;
+        }
+        guest_paths.push(guest_path.clone());
+
+        if let Some(host_path) = host_path {
+            resolved.push(ResolvedMount {
+                host_path,
+                guest_path: guest_path.to_string_lossy().to_string(),
+                readonly: mount.is_readonly(),
+            });
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(resolved)
+    } else {
+        Err(errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    fn mount(host_path: &str, guest_path: &str, readonly: Option<bool>) -> MountPoint {
+        MountPoint {
+            host_path: host_path.to_string(),
+            guest_path: guest_path.to_string(),
+            readonly,
+        }
+    }
+
+    fn resolve(tmp: &TempDir, mounts: &[MountPoint]) -> Result<Vec<String>, Vec<String>> {
+        let vars = HashMap::from([("src".to_string(), tmp.join("src").display().to_string())]);
+        let vars = Variables::new(&vars, "", &tmp.join("cozyboot.toml"));
+        resolve_mounts(mounts, &vars)
+            .map(|resolved| resolved.iter().map(ResolvedMount::to_arg).collect())
+            .map_err(|errors| {
+                errors
+                    .iter()
+                    .map(|error| format!("{} {} {}", error.index, error.field, error.message))
+                    .collect()
+            })
+    }
+
+    #[test]
+    fn renders_mount_arguments() {
+        let tmp = TempDir::new("mounts").with_files(&["src/a", "data/b"]);
+        let data = tmp.join("data").display().to_string();
+        let mounts = [
+            mount("$(src)", "/mnt/./src/", None),
+            mount(&data, "/data", Some(false)),
+        ];
+        assert_eq!(
+            resolve(&tmp, &mounts).unwrap(),
+            [
+                format!("{}:/mnt/src:ro", tmp.join("src").display()),
+                format!("{}:/data:rw", data)
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_paths_that_would_split_the_argument() {
+        let tmp = TempDir::new("mount-colons").with_files(&["a:b/c", "src/a"]);
+        let colon = tmp.join("a:b").display().to_string();
+        let errors = resolve(
+            &tmp,
+            &[mount(&colon, "/a", None), mount("$(src)", "/b:ro", None)],
+        )
+        .unwrap_err();
+        assert_eq!(errors.len(), 2, "{:?}", errors);
+        assert!(
+            errors[0].starts_with("0 host_path mount host path"),
+            "{}",
+            errors[0]
+        );
+        assert!(errors[1].starts_with("1 guest_path guest path '/b:ro' contains ':'"));
+    }
+
+    #[test]
+    fn reports_every_bad_mount() {
+        let tmp = TempDir::new("mount-errors").with_files(&["src/a"]);
+        let errors = resolve(
+            &tmp,
+            &[
+                mount("$(src)", "/mnt", None),
+                mount("$(src)", "/mnt/inner", None),
+                mount("$(src)", "relative", None),
+                mount("$(src)", "/x/../etc", None),
+                mount("$(nope)", "/y", None),
+                mount("/does/not/exist", "/z", None),
+            ],
+        )
+        .unwrap_err();
+        let fields: Vec<String> = errors
+            .iter()
+            .map(|error| error.splitn(3, ' ').take(2).collect::<Vec<_>>().join(" "))
+            .collect();
+        assert_eq!(
+            fields,
+            [
+                "1 guest_path",
+                "2 guest_path",
+                "3 guest_path",
+                "4 host_path",
+                "5 host_path"
+            ]
+        );
+        assert!(errors[0].ends_with("overlaps with '/mnt'"), "{}", errors[0]);
+    }
+}
@@ -0,0 +1,361 @@
+use colorize::*;
+use serde::de::{self, Deserialize, Deserializer, MapAccess, SeqAccess, Visitor};
+use std::fmt;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use toml::Spanned;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// A position in a config file, 1-based like editors show it
+#[derive(Debug, Clone)]
+pub struct Location {
+    pub file: PathBuf,
+    pub line: usize,
+    pub column: usize,
+    /// Number of characters to underline
+    pub length: usize,
+    pub source_line: String,
+}
+
+impl Location {
+    /// Build a location from a byte range into `content`
+    pub fn from_span(file: &Path, content: &str, span: Range<usize>) -> Self {
+        let start = span.start.min(content.len());
+        let line_start = content[..start].rfind('\n').map(|idx| idx + 1).unwrap_or(0);
+        let line_end = content[start..]
+            .find('\n')
+            .map(|idx| start + idx)
+            .unwrap_or(content.len());
+        let source_line = content[line_start..line_end]
+            .trim_end_matches('\r')
+            .to_string();
+
+        let line = content[..start].matches('\n').count() + 1;
+        let column = content[line_start..start].chars().count() + 1;
+        let end = span.end.clamp(start, line_end);
+        let length = content[start..end].chars().count().max(1);
+
+        Location {
+            file: file.to_path_buf(),
+            line,
+            column,
+            length,
+            source_line,
+        }
+    }
+}
+
+/// A single problem found while loading or validating the config
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub location: Option<Box<Location>>,
+    /// A suggested fix
+    pub help: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn error(message: impl Into<String>) -> Self {
+        Diagnostic {
+            severity: Severity::Error,
+            message: message.into(),
+            location: None,
+            help: None,
+        }
+    }
+
+    pub fn warning(message: impl Into<String>) -> Self {
+        Diagnostic {
+            severity: Severity::Warning,
+            message: message.into(),
+            location: None,
+            help: None,
+        }
+    }
+
+    pub fn at(mut self, location: Option<Location>) -> Self {
+        self.location = location.map(Box::new);
+        self
+    }
+
+    pub fn with_help(mut self, help: impl Into<String>) -> Self {
+        self.help = Some(help.into());
+        self
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+
+    // Everything below the headline: location, caret snippet and help
+    fn write_details(&self, f: &mut impl fmt::Write) -> fmt::Result {
+        if let Some(location) = &self.location {
+            let gutter = location.line.to_string().len();
+            writeln!(
+                f,
+                "{:gutter$}--> {}:{}:{}",
+                "",
+                location.file.display(),
+                location.line,
+                location.column
+            )?;
+            writeln!(f, "{:gutter$} |", "")?;
+            writeln!(f, "{} | {}", location.line, location.source_line)?;
+            writeln!(
+                f,
+                "{:gutter$} | {:pad$}{}",
+                "",
+                "",
+                "^".repeat(location.length),
+                pad = location.column - 1
+            )?;
+        }
+        if let Some(help) = &self.help {
+            writeln!(f, "  = help: {}", help)?;
+        }
+        Ok(())
+    }
+}
+
+impl From<String> for Diagnostic {
+    fn from(message: String) -> Self {
+        Diagnostic::error(message)
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}: {}", self.severity, self.message)?;
+        self.write_details(f)
+    }
+}
+
+/// Print diagnostics to stderr, errors first, with the headline coloured by severity
+pub fn report(diagnostics: &[Diagnostic]) {
+    let mut sorted: Vec<&Diagnostic> = diagnostics.iter().collect();
+    sorted.sort_by_key(|diagnostic| diagnostic.severity);
+
+    for diagnostic in sorted {
+        let headline = format!("{}: {}", diagnostic.severity, diagnostic.message);
+        match diagnostic.severity {
+            Severity::Error => eprintln!("{}", headline.red()),
+            Severity::Warning => eprintln!("{}", headline.yellow()),
+        }
+
+        let mut details = String::new();
+        let _ = diagnostic.write_details(&mut details);
+        eprint!("{}", details);
+    }
+}
+
+/// Find where a dotted key path is written in a TOML file.
+///
+/// Array entries are addressed by index (`mounts.0.host_path`). When the
+/// exact key is not written out, e.g. it was left to its default, the closest
+/// written ancestor key or table header is returned instead.
+pub fn locate_key(file: &Path, content: &str, path: &[String]) -> Option<Location> {
+    let root: Node = toml::from_str(content).ok()?;
+    let mut written = Vec::new();
+    root.collect(&mut Vec::new(), &mut written);
+
+    let (_, span) = written
+        .into_iter()
+        .filter(|(keys, _)| !keys.is_empty() && path.starts_with(keys))
+        .max_by_key(|(keys, _)| keys.len())?;
+    Some(Location::from_span(file, content, span))
+}
+
+// A parsed TOML document that remembers where each key and array entry is
+// written, as the parser sees it: keys inside strings are not keys
+enum Node {
+    Table(Vec<(Spanned<String>, Spanned<Node>)>),
+    Array(Vec<Spanned<Node>>),
+    Scalar,
+}
+
+impl Node {
+    // Record the span of every key and array entry below `prefix`
+    fn collect(&self, prefix: &mut Vec<String>, out: &mut Vec<(Vec<String>, Range<usize>)>) {
+        let children: Vec<(String, Range<usize>, &Node)> = match self {
+            Node::Table(entries) => entries
+                .iter()
+                .map(|(key, value)| (key.get_ref().clone(), key.span(), value.get_ref()))
+                .collect(),
+            Node::Array(items) => items
+                .iter()
+                .enumerate()
+                .map(|(index, item)| (index.to_string(), item.span(), item.get_ref()))
+                .collect(),
+            Node::Scalar => return,
+        };
+        for (key, span, child) in children {
+            prefix.push(key);
+            out.push((prefix.clone(), span));
+            child.collect(prefix, out);
+            prefix.pop();
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Node {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct NodeVisitor;
+
+        impl<'de> Visitor<'de> for NodeVisitor {
+            type Value = Node;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "a TOML value")
+            }
+
+            fn visit_bool<E: de::Error>(self, _: bool) -> Result<Node, E> {
+                Ok(Node::Scalar)
+            }
+
+            fn visit_i64<E: de::Error>(self, _: i64) -> Result<Node, E> {
+                Ok(Node::Scalar)
+            }
+
+            fn visit_u64<E: de::Error>(self, _: u64) -> Result<Node, E> {
+                Ok(Node::Scalar)
+            }
+
+            fn visit_f64<E: de::Error>(self, _: f64) -> Result<Node, E> {
+                Ok(Node::Scalar)
+            }
+
+            fn visit_str<E: de::Error>(self, _: &str) -> Result<Node, E> {
+                Ok(Node::Scalar)
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Node, A::Error> {
+                let mut items = Vec::new();
+                while let Some(item) = seq.next_element()? {
+                    items.push(item);
+                }
+                Ok(Node::Array(items))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Node, A::Error> {
+                let mut entries = Vec::new();
+                while let Some(entry) = map.next_entry()? {
+                    entries.push(entry);
+                }
+                Ok(Node::Table(entries))
+            }
+        }
+
+        deserializer.deserialize_any(NodeVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"[main]
+motd = """
+kern_root = "not a key"
+"""
+kern_root = "/kern"
+
+[bootargs]
+extra = [
+    "quiet",
+    "splash",
+]
+
+[[mounts]]
+host_path = "/a"
+
+[[mounts]]
+host_path = "/b"
+options = { mode = "ro" }
+"#;
+
+    fn locate(path: &str) -> Option<(usize, usize, String)> {
+        let path: Vec<String> = path.split('.').map(str::to_string).collect();
+        locate_key(Path::new("cozyboot.toml"), CONFIG, &path).map(|location| {
+            let text: String = location
+                .source_line
+                .chars()
+                .skip(location.column - 1)
+                .take(location.length)
+                .collect();
+            (location.line, location.column, text)
+        })
+    }
+
+    #[test]
+    fn keys_inside_multi_line_strings_are_not_keys() {
+        assert_eq!(locate("main.kern_root"), Some((5, 1, "kern_root".into())));
+    }
+
+    #[test]
+    fn locates_array_entries_by_index() {
+        assert_eq!(
+            locate("bootargs.extra.1"),
+            Some((10, 5, "\"splash\"".into()))
+        );
+        assert_eq!(
+            locate("mounts.1.host_path"),
+            Some((17, 1, "host_path".into()))
+        );
+        assert_eq!(
+            locate("mounts.0.host_path"),
+            Some((14, 1, "host_path".into()))
+        );
+    }
+
+    #[test]
+    fn falls_back_to_the_closest_written_key() {
+        assert_eq!(
+            locate("mounts.1.options.mode"),
+            Some((18, 13, "mode".into()))
+        );
+        assert_eq!(
+            locate("mounts.1.guest_path"),
+            Some((16, 1, "[[mounts]]".into()))
+        );
+        assert_eq!(locate("main.debug"), Some((1, 2, "main".into())));
+        assert_eq!(locate("runtime.timeout"), None);
+    }
+
+    #[test]
+    fn unparseable_files_have_no_locations() {
+        let path = ["main".to_string()];
+        assert!(locate_key(Path::new("x.toml"), "[main", &path).is_none());
+    }
+
+    #[test]
+    fn underlines_the_location() {
+        let location = Location::from_span(Path::new("cozyboot.toml"), CONFIG, 46..55);
+        let diagnostic = Diagnostic::error("kern_root does not exist")
+            .at(Some(location))
+            .with_help("create it");
+        assert_eq!(
+            diagnostic.to_string(),
+            "error: kern_root does not exist\n \
+             --> cozyboot.toml:5:1\n  \
+             |\n\
+             5 | kern_root = \"/kern\"\n  \
+             | ^^^^^^^^^\n  \
+             = help: create it\n"
+        );
+    }
+}
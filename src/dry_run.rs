@@ -0,0 +1,140 @@
+use boot::BootPlan;
+use clap::ValueEnum;
+use serde::Serialize;
+use std::path::PathBuf;
+
+/// Output format for `--dry-run`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// A single shell-quoted command line
+    #[default]
+    Shell,
+    /// A JSON object with program, args, env and cwd
+    Json,
+}
+
+#[derive(Debug, Serialize)]
+struct Invocation<'a> {
+    program: String,
+    args: &'a [String],
+    env: &'a [(String, String)],
+    cwd: String,
+}
+
+impl<'a> Invocation<'a> {
+    fn from_plan(plan: &'a BootPlan) -> Self {
+        // Without an explicit cwd the child inherits ours
+        let cwd = match &plan.cwd {
+            Some(dir) => dir.clone(),
+            None => std::env::current_dir().unwrap_or_else(|_| PathBuf::from(".")),
+        };
+
+        Invocation {
+            program: plan.program.to_string_lossy().to_string(),
+            args: &plan.args,
+            env: &plan.env,
+            cwd: cwd.to_string_lossy().to_string(),
+        }
+    }
+}
+
+/// Print the command that would be run instead of running it
+pub fn print_invocation(
+    plan: &BootPlan,
+    format: OutputFormat,
+) -> Result<(), Box<dyn std::error::Error>> {
+    println!("{}", render(plan, format)?);
+    Ok(())
+}
+
+// The text print_invocation prints, without the trailing newline
+fn render(plan: &BootPlan, format: OutputFormat) -> Result<String, serde_json::Error> {
+    let invocation = Invocation::from_plan(plan);
+
+    match format {
+        OutputFormat::Shell => {
+            let mut line = format!("cd {} &&", shell_quote(&invocation.cwd));
+            for (key, value) in invocation.env {
+                line.push(' ');
+                line.push_str(&shell_quote(&format!("{}={}", key, value)));
+            }
+            for word in std::iter::once(&invocation.program).chain(invocation.args.iter()) {
+                line.push(' ');
+                line.push_str(&shell_quote(word));
+            }
+            Ok(line)
+        }
+        OutputFormat::Json => serde_json::to_string_pretty(&invocation),
+    }
+}
+
+/// Quote a word for POSIX shells, leaving plain words untouched
+pub fn shell_quote(word: &str) -> String {
+    let is_plain = !word.is_empty()
+        && word.chars().all(|c| {
+            c.is_ascii_alphanumeric()
+                || matches!(c, '-' | '_' | '.' | '/' | ':' | '=' | ',' | '+' | '@' | '%')
+        });
+
+    if is_plain {
+        word.to_string()
+    } else {
+        format!("'{}'", word.replace('\'', "'\\''"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plan() -> BootPlan {
+        BootPlan::builder("/opt/cozy os/cozy-os")
+            .args(["--kern-root", "/kern", "--motd=it's cozy", ""])
+            .env("COZY_LOG", "debug")
+            .current_dir("/work")
+            .build()
+    }
+
+    #[test]
+    fn quotes_only_what_the_shell_would_split() {
+        assert_eq!(shell_quote("--kern-root=/a/b:c"), "--kern-root=/a/b:c");
+        assert_eq!(shell_quote("two words"), "'two words'");
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+        assert_eq!(shell_quote("$HOME"), "'$HOME'");
+        assert_eq!(shell_quote(""), "''");
+    }
+
+    #[test]
+    fn renders_a_shell_command_line() {
+        assert_eq!(
+            render(&plan(), OutputFormat::Shell).unwrap(),
+            "cd /work && COZY_LOG=debug '/opt/cozy os/cozy-os' --kern-root /kern \
+             '--motd=it'\\''s cozy' ''"
+        );
+    }
+
+    #[test]
+    fn renders_json() {
+        let json: serde_json::Value =
+            serde_json::from_str(&render(&plan(), OutputFormat::Json).unwrap()).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "program": "/opt/cozy os/cozy-os",
+                "args": ["--kern-root", "/kern", "--motd=it's cozy", ""],
+                "env": [["COZY_LOG", "debug"]],
+                "cwd": "/work",
+            })
+        );
+    }
+
+    #[test]
+    fn defaults_the_cwd_to_ours() {
+        let plan = BootPlan::builder("cozy-os").build();
+        let cwd = std::env::current_dir().unwrap();
+        assert_eq!(
+            render(&plan, OutputFormat::Shell).unwrap(),
+            format!("cd {} && cozy-os", shell_quote(&cwd.to_string_lossy()))
+        );
+    }
+}
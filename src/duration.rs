@@ -0,0 +1,206 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// A duration written for humans: `500ms`, `90s`, `5m`, `1h30m`.
+/// A bare number is read as seconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct HumanDuration(pub Duration);
+
+impl HumanDuration {
+    pub fn as_duration(self) -> Duration {
+        self.0
+    }
+}
+
+impl From<Duration> for HumanDuration {
+    fn from(duration: Duration) -> Self {
+        HumanDuration(duration)
+    }
+}
+
+impl FromStr for HumanDuration {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let trimmed = input.trim();
+        if trimmed.is_empty() {
+            return Err("empty duration".to_string());
+        }
+        if trimmed.bytes().all(|b| b.is_ascii_digit()) {
+            return trimmed
+                .parse::<u64>()
+                .map(|seconds| HumanDuration(Duration::from_secs(seconds)))
+                .map_err(|_| format!("invalid duration '{}': too long", input));
+        }
+
+        let mut total = Duration::ZERO;
+        let mut rest = trimmed;
+        while !rest.is_empty() {
+            let digits = rest
+                .find(|c: char| !c.is_ascii_digit() && c != '.')
+                .unwrap_or(rest.len());
+            let unit_end = rest[digits..]
+                .find(|c: char| c.is_ascii_digit())
+                .map_or(rest.len(), |idx| digits + idx);
+            let (number, unit) = (&rest[..digits], rest[digits..unit_end].trim());
+
+            let value: f64 = number.parse().map_err(|_| {
+                format!(
+                    "invalid duration '{}': expected a number before '{}'",
+                    input, unit
+                )
+            })?;
+            let scale = match unit {
+                "ms" => 0.001,
+                "s" | "sec" | "secs" => 1.0,
+                "m" | "min" | "mins" => 60.0,
+                "h" | "hr" | "hrs" => 3600.0,
+                "" => {
+                    return Err(format!(
+                        "invalid duration '{}': missing unit after {}",
+                        input, number
+                    ))
+                }
+                other => {
+                    return Err(format!(
+                        "invalid duration '{}': unknown unit '{}' (use ms, s, m or h)",
+                        input, other
+                    ))
+                }
+            };
+            total = Duration::try_from_secs_f64(value * scale)
+                .ok()
+                .and_then(|part| total.checked_add(part))
+                .ok_or_else(|| format!("invalid duration '{}': too long", input))?;
+            rest = rest[unit_end..].trim_start();
+        }
+
+        Ok(HumanDuration(total))
+    }
+}
+
+impl fmt::Display for HumanDuration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let millis = self.0.as_millis();
+        if !millis.is_multiple_of(1000) {
+            write!(f, "{}ms", millis)
+        } else {
+            let seconds = self.0.as_secs();
+            match seconds {
+                s if s >= 3600 && s.is_multiple_of(3600) => write!(f, "{}h", s / 3600),
+                s if s >= 60 && s.is_multiple_of(60) => write!(f, "{}m", s / 60),
+                s => write!(f, "{}s", s),
+            }
+        }
+    }
+}
+
+impl Serialize for HumanDuration {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for HumanDuration {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl serde::de::Visitor<'_> for Visitor {
+            type Value = HumanDuration;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(
+                    f,
+                    "a duration such as \"90s\" or \"5m\", or a number of seconds"
+                )
+            }
+
+            fn visit_u64<E: serde::de::Error>(self, seconds: u64) -> Result<Self::Value, E> {
+                Ok(HumanDuration(Duration::from_secs(seconds)))
+            }
+
+            fn visit_i64<E: serde::de::Error>(self, seconds: i64) -> Result<Self::Value, E> {
+                u64::try_from(seconds)
+                    .map(|seconds| HumanDuration(Duration::from_secs(seconds)))
+                    .map_err(|_| E::custom("duration must not be negative"))
+            }
+
+            fn visit_str<E: serde::de::Error>(self, text: &str) -> Result<Self::Value, E> {
+                text.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(input: &str) -> Result<Duration, String> {
+        input
+            .parse::<HumanDuration>()
+            .map(HumanDuration::as_duration)
+    }
+
+    #[test]
+    fn parses_units() {
+        assert_eq!(parse("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse("5 min"), Ok(Duration::from_secs(300)));
+        assert_eq!(parse("2h"), Ok(Duration::from_secs(7200)));
+        assert_eq!(parse("1h30m"), Ok(Duration::from_secs(5400)));
+        assert_eq!(parse("1m 30s"), Ok(Duration::from_secs(90)));
+    }
+
+    #[test]
+    fn parses_fractions() {
+        assert_eq!(parse("1.5s"), Ok(Duration::from_millis(1500)));
+        assert_eq!(parse("0.5h"), Ok(Duration::from_secs(1800)));
+    }
+
+    #[test]
+    fn bare_numbers_are_seconds() {
+        assert_eq!(parse("42"), Ok(Duration::from_secs(42)));
+        assert_eq!(parse(" 0 "), Ok(Duration::ZERO));
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        assert!(parse("").is_err());
+        assert!(parse("5").is_ok());
+        assert!(parse("5x").unwrap_err().contains("unknown unit 'x'"));
+        assert!(parse("1h30").unwrap_err().contains("missing unit"));
+        assert!(parse("ms").unwrap_err().contains("expected a number"));
+        assert!(parse("-5s").is_err());
+    }
+
+    #[test]
+    fn rejects_overflow() {
+        assert!(parse("99999999999999999999h")
+            .unwrap_err()
+            .contains("too long"));
+        assert!(parse("99999999999999999999")
+            .unwrap_err()
+            .contains("too long"));
+        assert!(
+            parse(&format!("{}s {}s", u64::MAX / 2 + 1, u64::MAX / 2 + 1))
+                .unwrap_err()
+                .contains("too long")
+        );
+    }
+
+    #[test]
+    fn displays_in_the_largest_whole_unit() {
+        assert_eq!(
+            HumanDuration(Duration::from_millis(1500)).to_string(),
+            "1500ms"
+        );
+        assert_eq!(HumanDuration(Duration::from_secs(45)).to_string(), "45s");
+        assert_eq!(HumanDuration(Duration::from_secs(120)).to_string(), "2m");
+        assert_eq!(HumanDuration(Duration::from_secs(7200)).to_string(), "2h");
+    }
+}
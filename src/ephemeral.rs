@@ -0,0 +1,184 @@
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::user_root::{copy_tree, is_writable, remove_tree};
+
+/// A throwaway copy of `user_root` for `boot --ephemeral`. The copy is made
+/// under the system temp directory when that is on the same filesystem as
+/// `user_root`, and beside `user_root` otherwise, so that `std::fs::copy`
+/// lets filesystems such as Btrfs and XFS share the data (a reflink) instead
+/// of duplicating it.
+///
+/// A copy is named after the boot that made it. Copies left behind by a boot
+/// that was killed before it could clean up are deleted by the next one.
+#[derive(Debug)]
+pub struct EphemeralRoot {
+    path: PathBuf,
+}
+
+impl EphemeralRoot {
+    /// Copy `user_root` into a new directory, first deleting any stale copies
+    pub fn create(user_root: &Path) -> Result<Self, String> {
+        Self::create_in(user_root, &copy_dir(user_root))
+    }
+
+    fn create_in(user_root: &Path, dir: &Path) -> Result<Self, String> {
+        let prefix = copy_prefix(user_root);
+        sweep(dir, &prefix);
+        let nanos = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .subsec_nanos();
+        let root = EphemeralRoot {
+            path: dir.join(format!("{}{}-{:08x}", prefix, std::process::id(), nanos)),
+        };
+
+        if let Err(e) = copy_tree(user_root, &root.path) {
+            // Never delete a directory that was already there
+            if e.kind() != io::ErrorKind::AlreadyExists {
+                let _ = root.remove();
+            }
+            return Err(format!(
+                "failed to copy {} to {}: {}",
+                user_root.display(),
+                root.path.display(),
+                e
+            ));
+        }
+        Ok(root)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Rename the copy so later boots do not sweep it up, and return where it
+    /// now is
+    pub fn keep(&self) -> Result<PathBuf, String> {
+        let name = self.path.file_name().unwrap_or_default().to_string_lossy();
+        let kept = self
+            .path
+            .with_file_name(name.replacen(".ephemeral-", ".kept-", 1));
+        fs::rename(&self.path, &kept)
+            .map_err(|e| format!("failed to keep {}: {}", self.path.display(), e))?;
+        Ok(kept)
+    }
+
+    /// Delete the copy; deleting it twice is not an error
+    pub fn remove(&self) -> Result<(), String> {
+        match remove_tree(&self.path) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(format!("failed to delete {}: {}", self.path.display(), e)),
+        }
+    }
+}
+
+// The temp directory when it can share data with user_root, else user_root's parent
+fn copy_dir(user_root: &Path) -> PathBuf {
+    let temp = std::env::temp_dir();
+    let device = |path: &Path| fs::metadata(path).map(|metadata| metadata.dev()).ok();
+    let parent = user_root.parent().filter(|parent| is_writable(parent));
+    match parent {
+        Some(parent) if device(&temp) != device(user_root) => parent.to_path_buf(),
+        _ => temp,
+    }
+}
+
+// Copies of one user_root are named `<prefix><pid>-<nanos>`
+fn copy_prefix(user_root: &Path) -> String {
+    let name = user_root
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    format!(".cozyboot-{}.ephemeral-", name)
+}
+
+// Delete copies in `dir` whose boot is no longer running
+fn sweep(dir: &Path, prefix: &str) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let pid = name
+            .strip_prefix(prefix)
+            .and_then(|rest| rest.split_once('-'))
+            .and_then(|(pid, _)| pid.parse().ok());
+        if pid.is_some_and(|pid| !running(pid)) {
+            let _ = remove_tree(&entry.path());
+        }
+    }
+}
+
+fn running(pid: libc::pid_t) -> bool {
+    // SAFETY: signal 0 only checks that the process exists
+    let sent = unsafe { libc::kill(pid, 0) == 0 };
+    sent || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    fn dead_pid() -> u32 {
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        child.wait().unwrap();
+        child.id()
+    }
+
+    #[test]
+    fn copies_user_root_and_removes_the_copy() {
+        let tmp = TempDir::new("ephemeral-copy").with_files(&["user/etc/motd"]);
+        let user_root = tmp.join("user");
+        let root = EphemeralRoot::create(&user_root).unwrap();
+        assert_eq!(root.path().parent(), Some(std::env::temp_dir().as_path()));
+
+        fs::write(root.path().join("etc/motd"), "changed").unwrap();
+        assert_eq!(
+            fs::read_to_string(user_root.join("etc/motd")).unwrap(),
+            "user/etc/motd"
+        );
+        root.remove().unwrap();
+        assert!(!root.path().exists());
+        root.remove().unwrap();
+    }
+
+    #[test]
+    fn sweeps_copies_left_by_dead_boots() {
+        let tmp = TempDir::new("ephemeral-sweep").with_files(&["user/a"]);
+        let user_root = tmp.join("user");
+        let prefix = copy_prefix(&user_root);
+        let stale = tmp.join(format!("{}{}-0", prefix, dead_pid()));
+        let live = tmp.join(format!("{}{}-0", prefix, std::process::id()));
+        let other = tmp.join(format!(".cozyboot-other.ephemeral-{}-0", dead_pid()));
+        let kept = tmp.join(format!(".cozyboot-user.kept-{}-0", dead_pid()));
+        for dir in [&stale, &live, &other, &kept] {
+            fs::create_dir(dir).unwrap();
+        }
+
+        let root = EphemeralRoot::create_in(&user_root, tmp.path()).unwrap();
+        assert!(!stale.exists());
+        for dir in [&live, &other, &kept, &root.path().to_path_buf()] {
+            assert!(dir.exists(), "{}", dir.display());
+        }
+    }
+
+    #[test]
+    fn keeps_a_copy_under_a_name_that_is_not_swept() {
+        let tmp = TempDir::new("ephemeral-keep").with_files(&["user/a"]);
+        let user_root = tmp.join("user");
+        let root = EphemeralRoot::create_in(&user_root, tmp.path()).unwrap();
+        let kept = root.keep().unwrap();
+        root.remove().unwrap();
+
+        let name = kept.file_name().unwrap().to_string_lossy();
+        assert!(name.starts_with(&format!(".cozyboot-user.kept-{}-", std::process::id())));
+        assert_eq!(fs::read_to_string(kept.join("a")).unwrap(), "user/a");
+        assert!(!root.path().exists());
+    }
+}
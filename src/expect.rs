@@ -0,0 +1,214 @@
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::Duration;
+
+use crate::config::CozyBootConfig;
+use crate::duration::HumanDuration;
+use crate::output::Stream;
+
+/// What happens when an `[[expect]]` pattern shows up
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExpectAction {
+    /// CozyOS has booted; with a timeout, not matching in time fails the boot
+    Ready,
+    /// Something went wrong: stop CozyOS and fail
+    Fail,
+    /// The run achieved what it was for: stop CozyOS and succeed
+    ExitSuccess,
+}
+
+impl fmt::Display for ExpectAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExpectAction::Ready => write!(f, "ready"),
+            ExpectAction::Fail => write!(f, "fail"),
+            ExpectAction::ExitSuccess => write!(f, "exit-success"),
+        }
+    }
+}
+
+/// Which output an `[[expect]]` rule watches
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum StreamFilter {
+    Stdout,
+    Stderr,
+    #[default]
+    Both,
+}
+
+impl StreamFilter {
+    pub fn accepts(self, stream: Stream) -> bool {
+        match self {
+            StreamFilter::Both => true,
+            StreamFilter::Stdout => stream == Stream::Stdout,
+            StreamFilter::Stderr => stream == Stream::Stderr,
+        }
+    }
+}
+
+/// One `[[expect]]` table
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExpectRule {
+    /// Regular expression matched against each line of output, including a
+    /// line still waiting for its newline such as a `Password: ` prompt
+    pub pattern: String,
+    #[serde(default)]
+    pub stream: StreamFilter,
+    pub action: ExpectAction,
+    /// For `ready` and `exit-success`: how long after starting the pattern
+    /// may take to appear. For `fail`: how long the output is watched.
+    pub timeout: Option<HumanDuration>,
+}
+
+impl ExpectRule {
+    pub fn compile(&self) -> Result<Expectation, String> {
+        Ok(Expectation {
+            pattern: compile_pattern(&self.pattern)?,
+            stream: self.stream,
+            action: self.action,
+            timeout: self.timeout.map(HumanDuration::as_duration),
+        })
+    }
+}
+
+// The regex crate matches in time linear in the line length, so long console
+// lines cannot stall the supervisor
+fn compile_pattern(pattern: &str) -> Result<Regex, String> {
+    Regex::new(pattern).map_err(|e| {
+        // Syntax errors come with their own snippet; keep only the message
+        let message = match &e {
+            regex::Error::Syntax(text) => text
+                .lines()
+                .last()
+                .unwrap_or_default()
+                .trim_start_matches("error: ")
+                .to_string(),
+            _ => e.to_string(),
+        };
+        format!("invalid pattern '{}': {}", pattern, message)
+    })
+}
+
+/// A compiled `[[expect]]` rule, checked against every line of output and
+/// every unfinished line as it grows
+#[derive(Debug, Clone)]
+pub struct Expectation {
+    pub pattern: Regex,
+    pub stream: StreamFilter,
+    pub action: ExpectAction,
+    pub timeout: Option<Duration>,
+}
+
+impl Expectation {
+    /// Whether a missing match by the timeout fails the boot
+    pub fn is_required(&self) -> bool {
+        self.action != ExpectAction::Fail && self.timeout.is_some()
+    }
+}
+
+/// Every rule that applies to a boot: `[runtime] ready_text` as a literal
+/// `ready` rule, followed by the `[[expect]]` tables
+pub fn expectations(config: &CozyBootConfig) -> Result<Vec<Expectation>, String> {
+    let ready_text = config
+        .runtime
+        .ready_text
+        .as_deref()
+        .map(|text| Expectation {
+            pattern: Regex::new(&regex::escape(text)).expect("an escaped literal is a valid regex"),
+            stream: StreamFilter::Both,
+            action: ExpectAction::Ready,
+            timeout: config.runtime.ready_timeout.map(HumanDuration::as_duration),
+        });
+
+    let mut expectations: Vec<Expectation> = ready_text.into_iter().collect();
+    for (index, rule) in config.expect.iter().enumerate() {
+        expectations.push(
+            rule.compile()
+                .map_err(|e| format!("expect[{}]: {}", index, e))?,
+        );
+    }
+    Ok(expectations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(pattern: &str, action: ExpectAction, timeout: Option<&str>) -> ExpectRule {
+        ExpectRule {
+            pattern: pattern.to_string(),
+            stream: StreamFilter::Both,
+            action,
+            timeout: timeout.map(|timeout| timeout.parse().unwrap()),
+        }
+    }
+
+    #[test]
+    fn compiles_rules() {
+        let expectation = rule(r"(?i)login:\s*$", ExpectAction::Ready, Some("60s"))
+            .compile()
+            .unwrap();
+        assert!(expectation.pattern.is_match("cozy LOGIN:  "));
+        assert!(!expectation.pattern.is_match("login: root"));
+        assert_eq!(expectation.timeout, Some(Duration::from_secs(60)));
+        assert!(expectation.is_required());
+    }
+
+    #[test]
+    fn fail_rules_are_never_required() {
+        assert!(!rule("panic", ExpectAction::Fail, Some("10s"))
+            .compile()
+            .unwrap()
+            .is_required());
+        assert!(!rule("login", ExpectAction::Ready, None)
+            .compile()
+            .unwrap()
+            .is_required());
+    }
+
+    #[test]
+    fn reports_invalid_patterns_in_one_line() {
+        let error = rule("boot (ok", ExpectAction::Ready, None)
+            .compile()
+            .unwrap_err();
+        assert_eq!(error, "invalid pattern 'boot (ok': unclosed group");
+    }
+
+    #[test]
+    fn ready_text_is_literal() {
+        let mut config: CozyBootConfig =
+            toml::from_str(include_str!("../minimal_config.toml")).unwrap();
+        config.runtime.ready_text = Some("ready (1.0) *".to_string());
+        let expectations = expectations(&config).unwrap();
+        assert_eq!(expectations.len(), 1);
+        assert!(expectations[0].pattern.is_match("system ready (1.0) *"));
+        assert!(!expectations[0].pattern.is_match("system ready 1.0"));
+    }
+
+    #[test]
+    fn long_lines_match_quickly() {
+        let line = "x".repeat(200_000);
+        let started = std::time::Instant::now();
+        assert!(!rule(".*login", ExpectAction::Ready, None)
+            .compile()
+            .unwrap()
+            .pattern
+            .is_match(&line));
+        assert!(!rule("(a+)+b", ExpectAction::Fail, None)
+            .compile()
+            .unwrap()
+            .pattern
+            .is_match(&"a".repeat(10_000)));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn stream_filter() {
+        assert!(StreamFilter::Both.accepts(Stream::Stderr));
+        assert!(StreamFilter::Stdout.accepts(Stream::Stdout));
+        assert!(!StreamFilter::Stdout.accepts(Stream::Stderr));
+    }
+}
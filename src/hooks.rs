@@ -0,0 +1,330 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::backend::ResolvedBoot;
+use crate::duration::HumanDuration;
+use crate::supervisor::{self, Outcome};
+use crate::vars::Variables;
+
+/// Variables describing the boot to hooks. They share the `COZYBOOT_`
+/// prefix with config overrides (`layers::ENV_PREFIX`), which skip these
+/// names so a hook that runs `boot` again does not read them as config keys.
+pub const ENV_VARS: &[&str] = &[
+    "COZYBOOT_KERN_ROOT",
+    "COZYBOOT_USER_ROOT",
+    "COZYBOOT_EXECUTABLE",
+    "COZYBOOT_PROFILE",
+    "COZYBOOT_BOOT_STRING",
+    "COZYBOOT_DEBUG",
+    "COZYBOOT_PHASE",
+    "COZYBOOT_LOG",
+    "COZYBOOT_EXIT_CODE",
+    "COZYBOOT_STOP_REASON",
+    "COZYBOOT_ATTEMPTS",
+];
+
+/// The `[hooks]` section: shell commands run around the boot
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HooksConfig {
+    /// Run in order before CozyOS starts; the first failure aborts the boot
+    #[serde(default)]
+    pub pre_boot: Vec<Hook>,
+    /// Run after CozyOS has stopped, whatever the outcome
+    #[serde(default)]
+    pub post_boot: Vec<Hook>,
+    /// Run after `post_boot` when the boot failed
+    #[serde(default)]
+    pub on_failure: Vec<Hook>,
+    /// Time limit for hooks that do not set their own
+    pub timeout: Option<HumanDuration>,
+}
+
+impl HooksConfig {
+    pub fn for_phase(&self, phase: Phase) -> &[Hook] {
+        match phase {
+            Phase::PreBoot => &self.pre_boot,
+            Phase::PostBoot => &self.post_boot,
+            Phase::OnFailure => &self.on_failure,
+        }
+    }
+}
+
+/// A hook command, either as a plain string or with its own timeout:
+/// `"make kernel"` or `{ command = "make kernel", timeout = "10m" }`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Hook {
+    Detailed(HookSpec),
+    Command(String),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HookSpec {
+    pub command: String,
+    pub timeout: Option<HumanDuration>,
+}
+
+impl Hook {
+    pub fn command(&self) -> &str {
+        match self {
+            Hook::Detailed(spec) => &spec.command,
+            Hook::Command(command) => command,
+        }
+    }
+
+    fn timeout(&self) -> Option<HumanDuration> {
+        match self {
+            Hook::Detailed(spec) => spec.timeout,
+            Hook::Command(_) => None,
+        }
+    }
+}
+
+/// When a group of hooks runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    PreBoot,
+    PostBoot,
+    OnFailure,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Phase::PreBoot => write!(f, "pre_boot"),
+            Phase::PostBoot => write!(f, "post_boot"),
+            Phase::OnFailure => write!(f, "on_failure"),
+        }
+    }
+}
+
+/// How a hook ended
+#[derive(Debug, Clone)]
+pub enum HookResult {
+    Finished(Outcome),
+    TimedOut(Duration),
+}
+
+impl HookResult {
+    pub fn success(&self) -> bool {
+        matches!(self, HookResult::Finished(outcome) if outcome.success())
+    }
+}
+
+impl fmt::Display for HookResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HookResult::Finished(outcome) => write!(f, "{}", outcome),
+            HookResult::TimedOut(timeout) => {
+                write!(f, "timed out after {}", HumanDuration::from(*timeout))
+            }
+        }
+    }
+}
+
+/// Progress reported while running hooks
+#[derive(Debug)]
+pub enum HookEvent<'a> {
+    Started {
+        phase: Phase,
+        command: &'a str,
+    },
+    Finished {
+        phase: Phase,
+        command: &'a str,
+        result: &'a HookResult,
+        runtime: Duration,
+    },
+}
+
+/// Environment describing a boot, passed to every hook
+pub fn boot_env(boot: &ResolvedBoot, profile: Option<&str>) -> Vec<(String, String)> {
+    vec![
+        ("COZYBOOT_KERN_ROOT".to_string(), boot.kern_root.clone()),
+        ("COZYBOOT_USER_ROOT".to_string(), boot.user_root.clone()),
+        (
+            "COZYBOOT_EXECUTABLE".to_string(),
+            boot.executable.to_string_lossy().to_string(),
+        ),
+        (
+            "COZYBOOT_PROFILE".to_string(),
+            profile.unwrap_or_default().to_string(),
+        ),
+        (
+            "COZYBOOT_BOOT_STRING".to_string(),
+            boot.boot_string.clone().unwrap_or_default(),
+        ),
+        (
+            "COZYBOOT_DEBUG".to_string(),
+            if boot.debug { "1" } else { "0" }.to_string(),
+        ),
+    ]
+}
+
+/// Run the hooks of `phase` with `sh -c`, after expanding `$(...)` in each
+/// command. Pre-boot hooks stop at the first failure; the other phases run
+/// every hook. Returns an error naming the failed hooks.
+pub async fn run(
+    phase: Phase,
+    config: &HooksConfig,
+    vars: &Variables,
+    env: &[(String, String)],
+    mut on_event: impl FnMut(HookEvent<'_>),
+) -> Result<(), String> {
+    let mut failures = Vec::new();
+
+    for hook in config.for_phase(phase) {
+        let command = vars.expand(hook.command()).map_err(|e| {
+            format!(
+                "failed to expand {} hook '{}': {}",
+                phase,
+                hook.command(),
+                e
+            )
+        })?;
+        on_event(HookEvent::Started {
+            phase,
+            command: &command,
+        });
+
+        let started = Instant::now();
+        let timeout = hook
+            .timeout()
+            .or(config.timeout)
+            .map(HumanDuration::as_duration);
+        let result = run_shell(&command, env, Some(phase), timeout).await;
+        on_event(HookEvent::Finished {
+            phase,
+            command: &command,
+            result: &result,
+            runtime: started.elapsed(),
+        });
+
+        if !result.success() {
+            failures.push(format!("'{}' {}", command, result));
+            if phase == Phase::PreBoot {
+                break;
+            }
+        }
+    }
+
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("{} hook failed: {}", phase, failures.join("; ")))
+    }
+}
+
+/// Run `command` with `sh -c`, killing it if it outlives `timeout`. Unless
+/// stdin is a terminal, the shell leads its own process group and the whole
+/// group is killed, so nothing it started outlives the timeout either.
+pub async fn run_shell(
+    command: &str,
+    env: &[(String, String)],
+    phase: Option<Phase>,
+    timeout: Option<Duration>,
+) -> HookResult {
+    let mut shell = tokio::process::Command::new("sh");
+    shell
+        .arg("-c")
+        .arg(command)
+        .envs(env.iter().map(|(key, value)| (key, value)))
+        .kill_on_drop(true);
+    if supervisor::use_process_group() {
+        shell.process_group(0);
+    }
+    if let Some(phase) = phase {
+        shell.env("COZYBOOT_PHASE", phase.to_string());
+    }
+    let mut child = match shell.spawn() {
+        Ok(child) => child,
+        Err(e) => return HookResult::Finished(Outcome::SpawnFailed(e.to_string())),
+    };
+
+    let status = match timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, child.wait()).await {
+            Ok(status) => status,
+            Err(_) => {
+                if let Some(pid) = child.id() {
+                    supervisor::signal_process(pid, libc::SIGKILL);
+                }
+                let _ = child.kill().await;
+                return HookResult::TimedOut(timeout);
+            }
+        },
+        None => child.wait().await,
+    };
+
+    HookResult::Finished(match status {
+        Ok(status) => Outcome::from_status(status),
+        Err(e) => Outcome::SpawnFailed(e.to_string()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn boot() -> ResolvedBoot {
+        ResolvedBoot {
+            executable: "/bin/cozy-os".into(),
+            kern_root: "/k".to_string(),
+            user_root: "/u".to_string(),
+            bootargs: Vec::new(),
+            mounts: Vec::new(),
+            allow_32bit: None,
+            allow_universal: None,
+            allow_64bit: None,
+            boot_string: None,
+            debug: true,
+        }
+    }
+
+    #[test]
+    fn boot_env_uses_the_listed_names() {
+        let env = boot_env(&boot(), Some("ci"));
+        assert!(env
+            .iter()
+            .all(|(name, _)| ENV_VARS.contains(&name.as_str())));
+        assert!(env.contains(&("COZYBOOT_KERN_ROOT".to_string(), "/k".to_string())));
+        assert!(env.contains(&("COZYBOOT_PROFILE".to_string(), "ci".to_string())));
+        assert!(env.contains(&("COZYBOOT_DEBUG".to_string(), "1".to_string())));
+    }
+
+    #[tokio::test]
+    async fn timeouts_kill_what_the_hook_started() {
+        let dir = crate::test_support::TempDir::new("hook-timeout");
+        let pid_file = dir.join("pid");
+        let command = format!("sleep 30 & echo $! > {}; wait", pid_file.display());
+        let result = run_shell(&command, &[], None, Some(Duration::from_millis(500))).await;
+        assert!(matches!(result, HookResult::TimedOut(_)));
+
+        // With a terminal on stdin only the shell itself can be killed
+        if supervisor::use_process_group() {
+            let sleep: u32 = std::fs::read_to_string(&pid_file)
+                .unwrap()
+                .trim()
+                .parse()
+                .unwrap();
+            assert!(crate::test_support::exited(sleep));
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_exit_codes() {
+        let result = run_shell("exit 3", &[], Some(Phase::PreBoot), None).await;
+        assert!(!result.success());
+        assert_eq!(result.to_string(), "exited with code 3");
+        let env = [("GREETING".to_string(), "hi".to_string())];
+        let result = run_shell(
+            "test \"$GREETING $COZYBOOT_PHASE\" = 'hi pre_boot'",
+            &env,
+            Some(Phase::PreBoot),
+            None,
+        )
+        .await;
+        assert!(result.success());
+    }
+}
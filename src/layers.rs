@@ -0,0 +1,477 @@
+use indexmap::IndexMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use toml::{Table, Value};
+
+use crate::diagnostics::{Diagnostic, Location};
+use crate::hooks;
+
+/// System-wide config, the lowest-priority layer
+pub const SYSTEM_CONFIG: &str = "/etc/cozyboot/cozyboot.toml";
+
+/// File name searched for when walking up from the current directory
+pub const PROJECT_CONFIG_NAME: &str = "cozyboot.toml";
+
+/// Prefix of environment variables that override config values.
+/// `COZYBOOT_MAIN__KERN_ROOT` sets `main.kern_root`: `__` separates table
+/// levels and the key is lowercased. The variables in `hooks::ENV_VARS`
+/// describe the boot to hook commands and are never read as overrides.
+pub const ENV_PREFIX: &str = "COZYBOOT_";
+
+/// One source of configuration values
+#[derive(Debug)]
+pub struct Layer {
+    /// Short name shown in `boot config sources`, e.g. "system" or "env"
+    pub name: &'static str,
+    /// The file this layer was read from, if any
+    pub path: Option<PathBuf>,
+    /// Raw file contents, kept for error reporting
+    pub content: Option<String>,
+    pub table: Table,
+}
+
+impl Layer {
+    /// Human-readable description of where the layer came from
+    pub fn describe(&self) -> String {
+        match &self.path {
+            Some(path) => format!("{} ({})", self.name, path.display()),
+            None => self.name.to_string(),
+        }
+    }
+}
+
+/// A resolved value and the layer that set it last
+#[derive(Debug, Clone)]
+pub struct ValueSource {
+    pub value: Value,
+    pub origin: String,
+    /// The file the value was written in, if it came from one
+    pub file: Option<PathBuf>,
+    /// The dotted key the value was written under in that file
+    pub file_key: String,
+}
+
+/// The merged config along with the layer that set each value
+#[derive(Debug)]
+pub struct MergedConfig {
+    pub table: Table,
+    /// Dotted key path -> where its current value came from
+    pub origins: IndexMap<String, ValueSource>,
+}
+
+impl MergedConfig {
+    /// Merge a layer's table over the current one and record where its values came from
+    pub fn apply_layer(&mut self, layer: &Layer) {
+        for (path, value) in leaves(&layer.table) {
+            self.record(
+                path.clone(),
+                ValueSource {
+                    value,
+                    origin: layer.describe(),
+                    file: layer.path.clone(),
+                    file_key: path,
+                },
+            );
+        }
+        merge_tables(&mut self.table, layer.table.clone());
+    }
+
+    /// Merge a profile table over the current one. Values are attributed to the
+    /// profile, pointing at where the profile itself was defined.
+    pub fn apply_profile(&mut self, name: &str, overlay: Table) {
+        for (path, value) in leaves(&overlay) {
+            let file_key = format!("profiles.{}.{}", name, path);
+            let file = self
+                .origins
+                .get(&file_key)
+                .and_then(|source| source.file.clone());
+            self.record(
+                path,
+                ValueSource {
+                    value,
+                    origin: format!("profile '{}'", name),
+                    file,
+                    file_key,
+                },
+            );
+        }
+        merge_tables(&mut self.table, overlay);
+    }
+
+    fn record(&mut self, path: String, source: ValueSource) {
+        // A replaced value drops whatever was recorded below or above it
+        let nested = format!("{}.", path);
+        self.origins.retain(|existing, _| {
+            !existing.starts_with(&nested) && !path.starts_with(&format!("{}.", existing))
+        });
+        self.origins.insert(path, source);
+    }
+
+    /// Find where the value at `path` was written. Falls back to the closest
+    /// recorded ancestor, or for a table to where its first value was written.
+    pub fn locate(&self, layers: &[Layer], path: &[String]) -> Option<Location> {
+        let (source, file_path) = match (1..=path.len()).rev().find_map(|depth| {
+            self.origins
+                .get(&path[..depth].join("."))
+                .map(|source| (depth, source))
+        }) {
+            Some((depth, source)) => {
+                let mut file_path: Vec<String> =
+                    source.file_key.split('.').map(str::to_string).collect();
+                file_path.extend(path[depth..].iter().cloned());
+                (source, file_path)
+            }
+            None => {
+                let prefix = format!("{}.", path.join("."));
+                let (key, source) = self
+                    .origins
+                    .iter()
+                    .find(|(key, _)| key.starts_with(&prefix))?;
+                // Drop the segments below `path`, keeping any profiles.<name> prefix
+                let below = key.split('.').count() - path.len();
+                let file_key: Vec<String> =
+                    source.file_key.split('.').map(str::to_string).collect();
+                (source, file_key[..file_key.len() - below].to_vec())
+            }
+        };
+
+        let file = source.file.as_ref()?;
+        let content = layers
+            .iter()
+            .find(|layer| layer.path.as_ref() == Some(file))
+            .and_then(|layer| layer.content.as_deref())?;
+        crate::diagnostics::locate_key(file, content, &file_path)
+    }
+}
+
+/// The user config file, honouring `XDG_CONFIG_HOME`
+pub fn user_config_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("cozyboot").join(PROJECT_CONFIG_NAME))
+}
+
+/// Find the nearest `cozyboot.toml` in the current directory or its parents
+pub fn find_project_config() -> Option<PathBuf> {
+    let cwd = env::current_dir().ok()?;
+    cwd.ancestors()
+        .map(|dir| dir.join(PROJECT_CONFIG_NAME))
+        .find(|candidate| candidate.is_file())
+}
+
+/// Collect every config layer in priority order, lowest first.
+///
+/// With an explicit `--config` path only that file is read in place of the
+/// system, user and project files. Environment overrides always apply.
+pub fn discover(explicit: Option<&Path>) -> Result<Vec<Layer>, Diagnostic> {
+    let mut layers = Vec::new();
+
+    match explicit {
+        Some(path) => {
+            if !path.exists() {
+                return Err(Diagnostic::error(format!(
+                    "Configuration file not found at {}",
+                    path.display()
+                ))
+                .with_help("run `boot init --path <file>` to create one"));
+            }
+            layers.push(read_layer("config", path)?);
+        }
+        None => {
+            let candidates = [
+                ("system", Some(PathBuf::from(SYSTEM_CONFIG))),
+                ("user", user_config_path()),
+                ("project", find_project_config()),
+            ];
+            for (name, path) in candidates {
+                if let Some(path) = path.filter(|path| path.is_file()) {
+                    // The project walk may land on the user or system file itself
+                    if layers
+                        .iter()
+                        .any(|layer: &Layer| layer.path.as_deref() == Some(path.as_path()))
+                    {
+                        continue;
+                    }
+                    layers.push(read_layer(name, &path)?);
+                }
+            }
+
+            if layers.is_empty() {
+                let user = user_config_path()
+                    .map(|path| path.display().to_string())
+                    .unwrap_or_else(|| "~/.config/cozyboot/cozyboot.toml".to_string());
+                return Err(Diagnostic::error(format!(
+                    "Configuration file not found (looked in {}, {} and ./{} and its parents)",
+                    SYSTEM_CONFIG, user, PROJECT_CONFIG_NAME
+                ))
+                .with_help("run `boot init` to create a user configuration"));
+            }
+        }
+    }
+
+    let env_table = env_overrides();
+    if !env_table.is_empty() {
+        layers.push(Layer {
+            name: "env",
+            path: None,
+            content: None,
+            table: env_table,
+        });
+    }
+
+    Ok(layers)
+}
+
+/// Merge layers in order, later layers overriding earlier ones
+pub fn merge(layers: &[Layer]) -> MergedConfig {
+    let mut merged = MergedConfig {
+        table: Table::new(),
+        origins: IndexMap::new(),
+    };
+    for layer in layers {
+        merged.apply_layer(layer);
+    }
+    merged
+}
+
+fn read_layer(name: &'static str, path: &Path) -> Result<Layer, Diagnostic> {
+    let content = fs::read_to_string(path).map_err(|e| {
+        Diagnostic::error(format!(
+            "Failed to read config file {}: {}",
+            path.display(),
+            e
+        ))
+    })?;
+    let table = toml::from_str(&content).map_err(|e: toml::de::Error| {
+        Diagnostic::error(format!(
+            "Failed to parse config file {}: {}",
+            path.display(),
+            e.message()
+        ))
+        .at(e
+            .span()
+            .map(|span| Location::from_span(path, &content, span)))
+    })?;
+    Ok(Layer {
+        name,
+        path: Some(path.to_path_buf()),
+        content: Some(content),
+        table,
+    })
+}
+
+// Build a table from COZYBOOT_* environment variables
+fn env_overrides() -> Table {
+    overrides_from(env::vars())
+}
+
+fn overrides_from(vars: impl Iterator<Item = (String, String)>) -> Table {
+    let mut vars: Vec<(String, String)> = vars
+        .filter(|(key, _)| key.starts_with(ENV_PREFIX) && key.len() > ENV_PREFIX.len())
+        .filter(|(key, _)| !hooks::ENV_VARS.contains(&key.as_str()))
+        .collect();
+    vars.sort();
+
+    let mut table = Table::new();
+    for (key, raw) in vars {
+        let path: Vec<String> = key[ENV_PREFIX.len()..]
+            .split("__")
+            .map(str::to_lowercase)
+            .collect();
+        if path.iter().any(String::is_empty) {
+            continue;
+        }
+        insert_path(&mut table, &path, parse_env_value(&raw));
+    }
+    table
+}
+
+// Interpret the value as TOML when it parses (numbers, booleans, arrays),
+// falling back to a plain string
+fn parse_env_value(raw: &str) -> Value {
+    toml::from_str::<Table>(&format!("value = {}", raw))
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| Value::String(raw.to_string()))
+}
+
+fn insert_path(table: &mut Table, path: &[String], value: Value) {
+    let (last, parents) = path.split_last().expect("path is never empty");
+    let mut current = table;
+    for key in parents {
+        let entry = current
+            .entry(key.clone())
+            .or_insert_with(|| Value::Table(Table::new()));
+        if !entry.is_table() {
+            *entry = Value::Table(Table::new());
+        }
+        current = entry.as_table_mut().expect("entry was just made a table");
+    }
+    current.insert(last.clone(), value);
+}
+
+// Flatten a table into (dotted path, value) pairs for every non-table value
+fn leaves(table: &Table) -> Vec<(String, Value)> {
+    fn walk(prefix: &str, table: &Table, out: &mut Vec<(String, Value)>) {
+        for (key, value) in table {
+            let path = if prefix.is_empty() {
+                key.clone()
+            } else {
+                format!("{}.{}", prefix, key)
+            };
+            match value {
+                Value::Table(child) => walk(&path, child, out),
+                _ => out.push((path, value.clone())),
+            }
+        }
+    }
+
+    let mut out = Vec::new();
+    walk("", table, &mut out);
+    out
+}
+
+/// Deep-merge `overlay` into `base`: tables are merged key by key, any other
+/// value replaces what was there before.
+pub fn merge_tables(base: &mut Table, overlay: Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(existing)), Value::Table(incoming)) => {
+                merge_tables(existing, incoming)
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layer(name: &'static str, content: &str) -> Layer {
+        let path = PathBuf::from(format!("/etc/{}.toml", name));
+        Layer {
+            name,
+            path: Some(path),
+            content: Some(content.to_string()),
+            table: toml::from_str(content).unwrap(),
+        }
+    }
+
+    fn env(vars: &[(&str, &str)]) -> Table {
+        overrides_from(
+            vars.iter()
+                .map(|(key, value)| (key.to_string(), value.to_string())),
+        )
+    }
+
+    #[test]
+    fn later_layers_win() {
+        let layers = [
+            layer(
+                "system",
+                "[main]\nkern_root = \"/sys\"\nuser_root = \"/user\"\n",
+            ),
+            layer("project", "[main]\nkern_root = \"/proj\"\n"),
+        ];
+        let merged = merge(&layers);
+        assert_eq!(merged.table["main"]["kern_root"].as_str(), Some("/proj"));
+        assert_eq!(merged.table["main"]["user_root"].as_str(), Some("/user"));
+        assert_eq!(
+            merged.origins["main.kern_root"].origin,
+            "project (/etc/project.toml)"
+        );
+        assert_eq!(
+            merged.origins["main.user_root"].origin,
+            "system (/etc/system.toml)"
+        );
+    }
+
+    #[test]
+    fn tables_merge_but_other_values_replace() {
+        let mut base: Table = toml::from_str("a = [1, 2]\n[t]\nx = 1\ny = 2\n").unwrap();
+        merge_tables(
+            &mut base,
+            toml::from_str("a = [3]\n[t]\ny = 20\nz = 30\n").unwrap(),
+        );
+        assert_eq!(
+            base,
+            toml::from_str::<Table>("a = [3]\n[t]\nx = 1\ny = 20\nz = 30\n").unwrap()
+        );
+    }
+
+    #[test]
+    fn replacing_a_table_drops_nested_origins() {
+        let layers = [
+            layer("system", "[vars]\nsrc = \"a\"\n"),
+            layer("project", "vars = 1\n"),
+        ];
+        let merged = merge(&layers);
+        assert!(!merged.origins.contains_key("vars.src"));
+        assert_eq!(merged.origins["vars"].origin, "project (/etc/project.toml)");
+    }
+
+    #[test]
+    fn profiles_are_attributed_to_their_definition() {
+        let layers = [layer(
+            "project",
+            "[profiles.ci.runtime]\ntimeout = \"5m\"\n",
+        )];
+        let mut merged = merge(&layers);
+        merged.apply_profile(
+            "ci",
+            toml::from_str("[runtime]\ntimeout = \"5m\"\n").unwrap(),
+        );
+        let source = &merged.origins["runtime.timeout"];
+        assert_eq!(source.origin, "profile 'ci'");
+        assert_eq!(source.file_key, "profiles.ci.runtime.timeout");
+        assert_eq!(source.file.as_deref(), Some(Path::new("/etc/project.toml")));
+    }
+
+    #[test]
+    fn env_overrides_parse_paths_and_values() {
+        let table = env(&[
+            ("COZYBOOT_MAIN__KERN_ROOT", "/k"),
+            ("COZYBOOT_SUPERVISOR__MAX_RESTARTS", "3"),
+            ("COZYBOOT_BIN__ALLOW_32BIT", "false"),
+            ("COZYBOOT_MAIN__USER_SKELETON", "[\"etc\", \"home\"]"),
+            ("COZYBOOT_", "ignored"),
+            ("COZYBOOT_MAIN____X", "ignored"),
+            ("OTHER", "ignored"),
+        ]);
+        let expected: Table = toml::from_str(
+            "[main]\nkern_root = \"/k\"\nuser_skeleton = [\"etc\", \"home\"]\n[supervisor]\nmax_restarts = 3\n[bin]\nallow_32bit = false\n").unwrap();
+        assert_eq!(table, expected);
+    }
+
+    #[test]
+    fn hook_variables_are_not_overrides() {
+        let hook_env: Vec<(&str, &str)> = hooks::ENV_VARS.iter().map(|name| (*name, "1")).collect();
+        assert!(env(&hook_env).is_empty());
+        assert!(!env(&[("COZYBOOT_DEFAULT_PROFILE", "ci")]).is_empty());
+        assert!(!env(&[("COZYBOOT_HOOKS__TIMEOUT", "1m")]).is_empty());
+    }
+
+    #[test]
+    fn locates_values_in_their_file() {
+        let layers = [
+            layer("system", "[main]\nkern_root = \"/sys\"\n"),
+            layer(
+                "project",
+                "# project\n[main]\nuser_root = \"/u\"\nkern_root = \"/proj\"\n",
+            ),
+        ];
+        let merged = merge(&layers);
+        let path = |key: &str| key.split('.').map(str::to_string).collect::<Vec<_>>();
+        let location = merged.locate(&layers, &path("main.kern_root")).unwrap();
+        assert_eq!(
+            (location.file.as_path(), location.line),
+            (Path::new("/etc/project.toml"), 4)
+        );
+        // A table points at where its first value was written
+        let location = merged.locate(&layers, &path("main")).unwrap();
+        assert_eq!(location.line, 2);
+        assert!(merged.locate(&layers, &path("runtime")).is_none());
+    }
+}
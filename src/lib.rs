@@ -0,0 +1,42 @@
+//! Configuration loading and launch planning for booting CozyOS.
+//!
+//! The `boot` binary is a thin command-line wrapper over this library; test
+//! runners and editor integrations can use the same pieces directly:
+//!
+//! - [`LoadedConfig`] reads and merges the config layers and profiles
+//! - [`validate::validate`] checks the result and reports [`Diagnostic`]s
+//! - [`expand_variables`] resolves `$(name)` references
+//! - [`ResolvedBoot`] and a [`Backend`] turn a config into a [`BootPlan`]
+//! - [`supervisor::supervise`] runs the boot and applies the restart policy
+
+pub mod backend;
+pub mod binscan;
+pub mod bootargs;
+pub mod build;
+pub mod config;
+pub mod diagnostics;
+pub mod digest;
+pub mod duration;
+pub mod ephemeral;
+pub mod expect;
+pub mod hooks;
+pub mod layers;
+pub mod logging;
+pub mod manifest;
+pub mod output;
+pub mod plan;
+pub mod profiles;
+pub mod runtime;
+pub mod snapshot;
+pub mod supervisor;
+#[cfg(test)]
+mod test_support;
+pub mod user_root;
+pub mod validate;
+pub mod vars;
+
+pub use backend::{Backend, BootOptions, ResolvedBoot};
+pub use config::{CozyBootConfig, LoadedConfig};
+pub use diagnostics::Diagnostic;
+pub use plan::{BootPlan, BootPlanBuilder};
+pub use vars::{expand_variables, Variables};
@@ -0,0 +1,537 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::vars::{expand_variables, Variables};
+
+/// The `[logging]` section
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LoggingConfig {
+    /// Directory for per-boot logs; `$(...)` references are expanded.
+    /// Logging is off when unset.
+    pub dir: Option<String>,
+    /// Start a new file for the same boot once a log reaches this size
+    pub max_size: ByteSize,
+    /// Log files kept in `dir`; the oldest boots are deleted first
+    pub max_files: usize,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            dir: None,
+            max_size: ByteSize(10 * 1024 * 1024),
+            max_files: 20,
+        }
+    }
+}
+
+impl LoggingConfig {
+    /// The expanded log directory, or None when logging is off
+    pub fn resolve_dir(&self, vars: &Variables) -> Result<Option<PathBuf>, String> {
+        self.dir
+            .as_deref()
+            .map(|dir| {
+                expand_variables(dir, vars)
+                    .map(PathBuf::from)
+                    .map_err(|e| format!("failed to expand logging.dir '{}': {}", dir, e))
+            })
+            .transpose()
+    }
+}
+
+/// A size written for humans: `512K`, `10MB`, `1G`. A bare number is bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ByteSize(pub u64);
+
+impl FromStr for ByteSize {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let trimmed = input.trim();
+        let digits = trimmed
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(trimmed.len());
+        let number: u64 = trimmed[..digits].parse().map_err(|_| {
+            format!(
+                "invalid size '{}': expected a number of bytes such as \"10MB\"",
+                input
+            )
+        })?;
+        let scale: u64 = match trimmed[digits..].trim().to_ascii_uppercase().as_str() {
+            "" | "B" => 1,
+            "K" | "KB" | "KIB" => 1024,
+            "M" | "MB" | "MIB" => 1024 * 1024,
+            "G" | "GB" | "GIB" => 1024 * 1024 * 1024,
+            other => {
+                return Err(format!(
+                    "invalid size '{}': unknown unit '{}' (use K, M or G)",
+                    input, other
+                ))
+            }
+        };
+        number
+            .checked_mul(scale)
+            .map(ByteSize)
+            .ok_or_else(|| format!("invalid size '{}': too large", input))
+    }
+}
+
+impl fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            bytes if bytes >= 1 << 30 && bytes.is_multiple_of(1 << 30) => {
+                write!(f, "{}G", bytes >> 30)
+            }
+            bytes if bytes >= 1 << 20 && bytes.is_multiple_of(1 << 20) => {
+                write!(f, "{}M", bytes >> 20)
+            }
+            bytes if bytes >= 1 << 10 && bytes.is_multiple_of(1 << 10) => {
+                write!(f, "{}K", bytes >> 10)
+            }
+            bytes => write!(f, "{}", bytes),
+        }
+    }
+}
+
+impl Serialize for ByteSize {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for ByteSize {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl serde::de::Visitor<'_> for Visitor {
+            type Value = ByteSize;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "a size such as \"10MB\", or a number of bytes")
+            }
+
+            fn visit_u64<E: serde::de::Error>(self, bytes: u64) -> Result<Self::Value, E> {
+                Ok(ByteSize(bytes))
+            }
+
+            fn visit_i64<E: serde::de::Error>(self, bytes: i64) -> Result<Self::Value, E> {
+                u64::try_from(bytes)
+                    .map(ByteSize)
+                    .map_err(|_| E::custom("size must not be negative"))
+            }
+
+            fn visit_str<E: serde::de::Error>(self, text: &str) -> Result<Self::Value, E> {
+                text.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
+/// A log shared between the supervisor and the output pumps
+pub type SharedLog = Arc<Mutex<BootLog>>;
+
+/// Append a line to `log` if there is one
+pub fn write_shared(log: Option<&SharedLog>, tag: &str, text: &str) {
+    if let Some(mut log) = log.and_then(|log| log.lock().ok()) {
+        log.write_line(tag, text);
+    }
+}
+
+/// The log file of one `boot` run, named `<timestamp>-<profile>.log` with
+/// anything but letters, digits and `_` in the profile name replaced by `_`.
+/// Once it reaches `max_size` the run continues in `<id>.1.log`, `<id>.2.log`...
+#[derive(Debug)]
+pub struct BootLog {
+    dir: PathBuf,
+    id: String,
+    segment: u32,
+    file: File,
+    written: u64,
+    max_size: u64,
+    max_files: usize,
+}
+
+impl BootLog {
+    /// Create the log for a new boot in `dir`, pruning old files first
+    pub fn create(
+        dir: &Path,
+        profile: Option<&str>,
+        config: &LoggingConfig,
+    ) -> Result<Self, String> {
+        fs::create_dir_all(dir)
+            .map_err(|e| format!("failed to create log directory {}: {}", dir.display(), e))?;
+
+        let (date, time) = utc_parts(SystemTime::now());
+        let base = format!(
+            "{}-{}-{}",
+            date.replace('-', ""),
+            time[..8].replace(':', ""),
+            file_label(profile.unwrap_or("default"))
+        );
+        // Two boots in the same second get distinct ids
+        let taken: Vec<String> = list(dir)?.into_iter().map(|entry| entry.id).collect();
+        let id = (1..)
+            .map(|n| {
+                if n == 1 {
+                    base.clone()
+                } else {
+                    format!("{}-{}", base, n)
+                }
+            })
+            .find(|id| !taken.contains(id))
+            .expect("some suffix is free");
+
+        let mut log = BootLog {
+            dir: dir.to_path_buf(),
+            file: open_segment(dir, &id, 0)?,
+            id,
+            segment: 0,
+            written: 0,
+            max_size: config.max_size.0.max(1),
+            max_files: config.max_files.max(1),
+        };
+        log.prune();
+        Ok(log)
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Path of the file currently written to
+    pub fn path(&self) -> PathBuf {
+        self.dir.join(segment_name(&self.id, self.segment))
+    }
+
+    /// Append `text` with a timestamp and a tag such as `stdout`
+    pub fn write_line(&mut self, tag: &str, text: &str) {
+        if self.written >= self.max_size {
+            if let Ok(file) = open_segment(&self.dir, &self.id, self.segment + 1) {
+                self.file = file;
+                self.segment += 1;
+                self.written = 0;
+                self.prune();
+            }
+        }
+
+        let (date, time) = utc_parts(SystemTime::now());
+        let line = format!("{}T{}Z [{}] {}\n", date, time, tag, text);
+        // A full disk must not take the boot down with it
+        if self.file.write_all(line.as_bytes()).is_ok() {
+            self.written += line.len() as u64;
+        }
+    }
+
+    // Delete the oldest boots, all their files at once, until at most
+    // `max_files` files remain. Only when this boot alone has more are its
+    // own oldest files deleted.
+    fn prune(&mut self) {
+        let Ok(entries) = list(&self.dir) else { return };
+        let mut remaining: usize = entries.iter().map(|entry| entry.files.len()).sum();
+        for entry in &entries {
+            if remaining <= self.max_files {
+                return;
+            }
+            if entry.id != self.id {
+                for file in &entry.files {
+                    let _ = fs::remove_file(file);
+                }
+                remaining -= entry.files.len();
+            }
+        }
+
+        let current = self.path();
+        let Some(own) = entries.into_iter().find(|entry| entry.id == self.id) else {
+            return;
+        };
+        for file in own
+            .files
+            .iter()
+            .filter(|file| **file != current)
+            .take(remaining.saturating_sub(self.max_files))
+        {
+            let _ = fs::remove_file(file);
+        }
+    }
+}
+
+/// A past boot found in the log directory
+#[derive(Debug, Clone)]
+pub struct LogEntry {
+    pub id: String,
+    /// Every file of the boot, in the order they were written
+    pub files: Vec<PathBuf>,
+    pub size: u64,
+}
+
+/// Every boot log in `dir`, oldest first
+pub fn list(dir: &Path) -> Result<Vec<LogEntry>, String> {
+    let read = fs::read_dir(dir)
+        .map_err(|e| format!("failed to read log directory {}: {}", dir.display(), e))?;
+
+    let mut segments: Vec<(String, u32, PathBuf, u64)> = Vec::new();
+    for entry in read.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let Some(stem) = name.strip_suffix(".log") else {
+            continue;
+        };
+        let (id, segment) = match stem
+            .rsplit_once('.')
+            .map(|(id, number)| (id, number.parse::<u32>()))
+        {
+            Some((id, Ok(segment))) => (id.to_string(), segment),
+            _ => (stem.to_string(), 0),
+        };
+        let size = entry.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+        segments.push((id, segment, entry.path(), size));
+    }
+    // Ids start with the timestamp, so they sort oldest first
+    segments.sort_by(|a, b| (id_order(&a.0), a.1).cmp(&(id_order(&b.0), b.1)));
+
+    let mut entries: Vec<LogEntry> = Vec::new();
+    for (id, _, path, size) in segments {
+        match entries.last_mut() {
+            Some(entry) if entry.id == id => {
+                entry.files.push(path);
+                entry.size += size;
+            }
+            _ => entries.push(LogEntry {
+                id,
+                files: vec![path],
+                size,
+            }),
+        }
+    }
+    Ok(entries)
+}
+
+// Sort key of a log id: a second boot in the same second is `<base>-2`, and
+// `<base>-10` must come after it
+fn id_order(id: &str) -> (&str, u32) {
+    match id
+        .rsplit_once('-')
+        .map(|(base, n)| (base, n.parse::<u32>()))
+    {
+        Some((base, Ok(n))) if n >= 2 => (base, n),
+        _ => (id, 1),
+    }
+}
+
+/// The full text of the boot log `id`
+pub fn read(dir: &Path, id: &str) -> Result<String, String> {
+    let entry = list(dir)?
+        .into_iter()
+        .find(|entry| entry.id == id)
+        .ok_or_else(|| format!("no boot log '{}' in {}", id, dir.display()))?;
+
+    let mut text = String::new();
+    for file in &entry.files {
+        let bytes =
+            fs::read(file).map_err(|e| format!("failed to read {}: {}", file.display(), e))?;
+        text.push_str(&String::from_utf8_lossy(&bytes));
+    }
+    Ok(text)
+}
+
+// The profile as it appears in a log id. Everything but letters, digits and
+// `_` becomes `_`: a `.` would read as a segment number, a trailing `-N` as a
+// same-second suffix and a `/` as a directory.
+fn file_label(profile: &str) -> String {
+    let label: String = profile
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if label.is_empty() {
+        "default".to_string()
+    } else {
+        label
+    }
+}
+
+fn segment_name(id: &str, segment: u32) -> String {
+    if segment == 0 {
+        format!("{}.log", id)
+    } else {
+        format!("{}.{}.log", id, segment)
+    }
+}
+
+fn open_segment(dir: &Path, id: &str, segment: u32) -> Result<File, String> {
+    let path = dir.join(segment_name(id, segment));
+    File::create(&path).map_err(|e| format!("failed to create log file {}: {}", path.display(), e))
+}
+
+/// `YYYY-MM-DD` and `HH:MM:SS.mmm` in UTC
+pub fn utc_parts(time: SystemTime) -> (String, String) {
+    let since_epoch = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    let seconds = since_epoch.as_secs();
+    let (days, of_day) = (seconds / 86400, seconds % 86400);
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    (
+        format!("{:04}-{:02}-{:02}", year, month, day),
+        format!(
+            "{:02}:{:02}:{:02}.{:03}",
+            of_day / 3600,
+            of_day % 3600 / 60,
+            of_day % 60,
+            since_epoch.subsec_millis()
+        ),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    fn touch(dir: &Path, names: &[&str]) {
+        for name in names {
+            fs::write(dir.join(name), name).unwrap();
+        }
+    }
+
+    fn ids(dir: &Path) -> Vec<String> {
+        list(dir)
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.id)
+            .collect()
+    }
+
+    #[test]
+    fn same_second_suffixes_sort_numerically() {
+        let tmp = TempDir::new("order");
+        let dir = tmp.path();
+        touch(
+            dir,
+            &[
+                "20260101-000000-ci-10.log",
+                "20260101-000000-ci.log",
+                "20260101-000000-ci-2.log",
+                "20251231-235959-ci.log",
+            ],
+        );
+        assert_eq!(
+            ids(dir),
+            [
+                "20251231-235959-ci",
+                "20260101-000000-ci",
+                "20260101-000000-ci-2",
+                "20260101-000000-ci-10"
+            ]
+        );
+    }
+
+    #[test]
+    fn groups_segments_by_boot() {
+        let tmp = TempDir::new("segments");
+        let dir = tmp.path();
+        touch(
+            dir,
+            &[
+                "20260101-000000-ci.2.log",
+                "20260101-000000-ci.log",
+                "20260101-000000-ci.1.log",
+                "notes.txt",
+            ],
+        );
+        let entries = list(dir).unwrap();
+        assert_eq!(entries.len(), 1);
+        let names: Vec<String> = entries[0]
+            .files
+            .iter()
+            .map(|file| file.file_name().unwrap().to_string_lossy().to_string())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "20260101-000000-ci.log",
+                "20260101-000000-ci.1.log",
+                "20260101-000000-ci.2.log"
+            ]
+        );
+        assert_eq!(
+            read(dir, "20260101-000000-ci").unwrap(),
+            "20260101-000000-ci.log20260101-000000-ci.1.log20260101-000000-ci.2.log"
+        );
+    }
+
+    #[test]
+    fn prunes_whole_boots() {
+        let tmp = TempDir::new("prune");
+        let dir = tmp.path();
+        touch(
+            dir,
+            &[
+                "20200101-000000-a.log",
+                "20200101-000000-a.1.log",
+                "20200102-000000-b.log",
+                "20200102-000000-b.1.log",
+            ],
+        );
+        let config = LoggingConfig {
+            dir: None,
+            max_size: ByteSize(1024),
+            max_files: 3,
+        };
+        let log = BootLog::create(dir, None, &config).unwrap();
+        // Deleting only a.log would have left a.1.log without its beginning
+        assert_eq!(ids(dir), ["20200102-000000-b", log.id()]);
+        assert_eq!(
+            list(dir)
+                .unwrap()
+                .iter()
+                .map(|entry| entry.files.len())
+                .sum::<usize>(),
+            3
+        );
+    }
+
+    #[test]
+    fn profile_names_cannot_break_log_names() {
+        let tmp = TempDir::new("profiles");
+        let dir = tmp.path();
+        let config = LoggingConfig::default();
+        let mut created = Vec::new();
+        for profile in ["v1.2", "ci-3", "a/b", ""] {
+            let mut log = BootLog::create(dir, Some(profile), &config).unwrap();
+            log.write_line("boot", profile);
+            created.push(log.id().to_string());
+        }
+        let labels: Vec<&str> = created.iter().map(|id| &id[16..]).collect();
+        assert_eq!(labels, ["v1_2", "ci_3", "a_b", "default"]);
+
+        let mut listed = ids(dir);
+        listed.sort();
+        let mut sorted = created.clone();
+        sorted.sort();
+        assert_eq!(listed, sorted);
+        // Each boot is one entry with one file, holding what it wrote
+        for entry in list(dir).unwrap() {
+            assert_eq!(entry.files.len(), 1);
+        }
+        assert!(read(dir, &created[2]).unwrap().ends_with("[boot] a/b\n"));
+    }
+}
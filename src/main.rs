@@ -1,25 +1,61 @@
-use clap::Parser;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, Args, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
 use colorize::*;
-use serde::{Deserialize, Serialize};
-use std::fs;
-use std::path::Path;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
 use std::process::Command;
-use tokio;
-use std::path::PathBuf;
-use dirs;
-use std::env;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+mod dry_run;
+
+use boot::duration::HumanDuration;
+use boot::ephemeral::EphemeralRoot;
+use boot::expect::ExpectAction;
+use boot::{
+    backend, binscan, diagnostics, expect, hooks, layers, logging, manifest, runtime, snapshot,
+    supervisor, user_root, validate,
+};
+use boot::{BootOptions, CozyBootConfig, Diagnostic, LoadedConfig, ResolvedBoot, Variables};
+use dry_run::OutputFormat;
+
+const EXIT_STATUS_HELP: &str = "\
+Exit status:
+  0        CozyOS shut down cleanly
+  1        boot itself failed (bad config, CozyOS could not be started)
+  N        CozyOS exited with code N
+  123      a [[expect]] rule with action = \"fail\" matched
+  124      the --timeout / [runtime] timeout limit was reached
+  125      a ready_text or [[expect]] pattern did not appear within its timeout
+  128+N    CozyOS was killed by signal N (e.g. 137 for SIGKILL), or boot was
+           interrupted by it before CozyOS started";
 
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
-struct Args {
-    /// Path to the configuration file (default: ~/.config/cozyboot/cozyboot.toml)
-    #[arg(short, long)]
+#[command(author, version, about, long_about = None, after_help = EXIT_STATUS_HELP)]
+struct Cli {
+    /// Path to the configuration file, replacing the system, user and project
+    /// config files (COZYBOOT_* environment overrides still apply)
+    #[arg(short, long, global = true)]
     config: Option<String>,
 
     /// Enable verbose output
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     verbose: bool,
 
+    /// Boot profile from [profiles.<name>] to apply (default: default_profile)
+    #[arg(short, long, global = true)]
+    profile: Option<String>,
+
+    /// Options for booting when no subcommand is given
+    #[command(flatten)]
+    run: RunArgs,
+
+    #[command(subcommand)]
+    command: Option<Commands>,
+}
+
+#[derive(Args, Debug, Clone)]
+struct RunArgs {
     /// Enable debug mode
     #[arg(short, long)]
     debug: bool,
@@ -27,173 +63,1253 @@ struct Args {
     /// Boot string to pass directly to CozyOS
     #[arg(short, long)]
     boot_string: Option<String>,
+
+    /// cozy-os executable to run (overrides [runtime] executable)
+    #[arg(long = "cozy-os", value_name = "PATH")]
+    cozy_os: Option<String>,
+
+    /// Print the cozy-os invocation instead of running it
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Output format for --dry-run
+    #[arg(long, value_enum, default_value_t = OutputFormat::Shell)]
+    format: OutputFormat,
+
+    /// Stop CozyOS after this long, e.g. 90s or 5m (overrides [runtime] timeout)
+    #[arg(long, value_name = "DURATION")]
+    timeout: Option<HumanDuration>,
+
+    /// Boot without running [build], even if the kernel is out of date
+    #[arg(long, conflicts_with = "force_build")]
+    no_build: bool,
+
+    /// Run [build] even if the kernel looks up to date
+    #[arg(long)]
+    force_build: bool,
+
+    /// Boot on a temporary copy of user_root that is deleted when CozyOS exits
+    #[arg(long)]
+    ephemeral: bool,
+
+    /// With --ephemeral, keep the copy when the boot fails so it can be inspected.
+    /// Kept copies are renamed `.cozyboot-<name>.kept-*` and are never deleted for you
+    #[arg(long, requires = "ephemeral")]
+    keep_on_failure: bool,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct CozyBootConfig {
-    main: MainConfig,
-    bootargs: std::collections::HashMap<String, String>,
-    bin: BinSettings,
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Boot CozyOS (the default when no subcommand is given)
+    Run(RunArgs),
+    /// Write a starter configuration (to the user config directory by default)
+    Init(InitArgs),
+    /// Validate the configuration without booting
+    Check,
+    /// Print the resolved configuration
+    Show,
+    /// Open the configuration in $EDITOR and validate it afterwards
+    Edit,
+    /// Inspect the layered configuration
+    Config {
+        #[command(subcommand)]
+        action: ConfigCommand,
+    },
+    /// List past boots from [logging] dir, or print the log of one
+    Logs(LogsArgs),
+    /// List the ELF and Mach-O binaries in user_root and those [bin] rejects
+    ScanBins(ScanBinsArgs),
+    /// Save, list and roll back copies of user_root
+    Snapshot {
+        #[command(subcommand)]
+        action: SnapshotCommand,
+    },
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct MainConfig {
-    kern_root: String,
-    user_root: String,
+#[derive(Args, Debug)]
+struct ScanBinsArgs {
+    /// Only list the binaries the [bin] policy rejects
+    #[arg(long)]
+    rejected: bool,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct BinSettings {
-    allow_32bit: Option<bool>,
-    allow_universal: Option<bool>,
-    allow_64bit: Option<bool>,
+#[derive(Subcommand, Debug)]
+enum SnapshotCommand {
+    /// Copy user_root into a new snapshot, hardlinking files unchanged since the last one
+    Create {
+        /// Name for the snapshot (default: the current UTC time)
+        name: Option<String>,
+    },
+    /// List snapshots, oldest first
+    List,
+    /// Replace user_root with a copy of a snapshot
+    Restore { id: String },
+    /// Delete a snapshot
+    Delete { id: String },
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct MountPoint {
-    host_path: String,
-    guest_path: String,
-    readonly: Option<bool>,
+#[derive(Args, Debug)]
+struct LogsArgs {
+    /// Print the log of the most recent boot
+    #[arg(long, conflicts_with = "id")]
+    last: bool,
+
+    /// Print the log of the boot with this id
+    #[arg(long)]
+    id: Option<String>,
 }
 
-// Add this function to handle config path resolution
-fn get_config_path(config_arg: Option<String>) -> PathBuf {
-    if let Some(path) = config_arg {
-        PathBuf::from(path)
-    } else {
-        let home = dirs::home_dir().expect("Could not find home directory");
-        home.join(".config/cozyboot/cozyboot.toml")
+#[derive(Args, Debug)]
+struct InitArgs {
+    /// Overwrite an existing configuration file
+    #[arg(short, long)]
+    force: bool,
+
+    /// Where to write the config: a file, or a directory to hold cozyboot.toml
+    /// (default: $XDG_CONFIG_HOME/cozyboot/cozyboot.toml)
+    #[arg(long)]
+    path: Option<PathBuf>,
+
+    /// Starting point for the new configuration
+    #[arg(short, long, value_enum, default_value_t = Template::Default)]
+    template: Template,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Template {
+    /// Every section with commented examples
+    Default,
+    /// Only the required sections
+    Minimal,
+}
+
+impl Template {
+    fn contents(self) -> &'static str {
+        match self {
+            Template::Default => include_str!("../default_config.toml"),
+            Template::Minimal => include_str!("../minimal_config.toml"),
+        }
     }
 }
 
-// Add this function to ensure config directory exists
-fn ensure_config_dir() -> Result<PathBuf, std::io::Error> {
-    let config_dir = dirs::home_dir()
-        .expect("Could not find home directory")
-        .join(".config/cozyboot");
+#[derive(Subcommand, Debug)]
+enum ConfigCommand {
+    /// Show which config layer set each value
+    Sources,
+}
+
+// Print an error in red and exit with status 1
+fn exit_with_error(message: impl Display) -> ! {
+    eprintln!("{}", format!("Error: {}", message).red());
+    std::process::exit(1);
+}
 
-    if !config_dir.exists() {
-        std::fs::create_dir_all(&config_dir)?;
-        
-        // Create default config file
-        let default_config = include_str!("../default_config.toml");
-        std::fs::write(config_dir.join("cozyboot.toml"), default_config)?;
+// Print diagnostics and exit with status 1
+fn exit_with_diagnostics(diagnostics: &[Diagnostic]) -> ! {
+    diagnostics::report(diagnostics);
+    std::process::exit(1);
+}
+
+// Read and merge every config layer, reporting what was read in verbose mode
+fn load_config(cli: &Cli) -> LoadedConfig {
+    let loaded = LoadedConfig::load(cli.config.as_deref().map(Path::new), cli.profile.as_deref())
+        .unwrap_or_else(|e| exit_with_diagnostics(&[e]));
+
+    if cli.verbose {
+        for layer in &loaded.layers {
+            println!(
+                "{}",
+                format!("Reading configuration from {}", layer.describe()).blue()
+            );
+            if let Some(content) = &layer.content {
+                println!("Config content:\n{}", content);
+            }
+        }
+        if let Some(profile) = &loaded.profile {
+            println!("{}", format!("Using profile '{}'", profile).blue());
+        }
     }
 
-    Ok(config_dir)
+    loaded
 }
 
-// Fix the expand_variables function
-fn expand_variables(path: &str) -> String {
-    let mut result = path.to_string();
-    
-    // Handle $(devroot) variable - default to current directory if not set
-    let devroot = env::var("DEVROOT").unwrap_or_else(|_| ".".to_string());
-    result = result.replace("$(devroot)", &devroot);
-    
-    // Convert to absolute path if it's relative
-    if let Ok(absolute_path) = std::fs::canonicalize(&result) {
-        absolute_path.to_string_lossy().to_string()
+// Validate the loaded config, printing every problem found; None if there were errors
+fn validated_config(loaded: &LoadedConfig) -> Option<CozyBootConfig> {
+    let validation = validate::validate(loaded);
+    diagnostics::report(&validation.diagnostics);
+    if validation.has_errors() {
+        None
     } else {
-        result
+        validation.config
     }
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args = Args::parse();
-    
-    // Ensure config directory exists
-    let _config_dir = ensure_config_dir()?;
-    
-    // Get config path
-    let config_path = get_config_path(args.config);
-    
-    if !config_path.exists() {
-        eprintln!("{}", format!("Error: Configuration file not found at {}", config_path.display()).red());
-        std::process::exit(1);
+// Resolve everything needed to boot from a validated config
+fn resolve_boot(
+    config: &CozyBootConfig,
+    config_path: &Path,
+    run: &RunArgs,
+    verbose: bool,
+) -> Result<ResolvedBoot, String> {
+    let options = BootOptions {
+        boot_string: run.boot_string.clone(),
+        debug: run.debug,
+        executable: run.cozy_os.clone(),
+    };
+    let boot = ResolvedBoot::resolve(config, config_path, &options)?;
+
+    if verbose {
+        println!(
+            "{}",
+            format!("Using cozy-os at {}", boot.executable.display()).blue()
+        );
+        if run.debug {
+            println!("{}", "Debug mode enabled".blue());
+        }
+    }
+
+    Ok(boot)
+}
+
+// Print every config layer and the layer that set each resolved value
+fn print_sources(loaded: &LoadedConfig) {
+    println!("{}", "Layers (lowest priority first):".blue());
+    for layer in &loaded.layers {
+        println!("  {}", layer.describe());
+    }
+
+    println!("{}", "Values:".blue());
+    let mut values: Vec<_> = loaded
+        .merged
+        .origins
+        .iter()
+        .map(|(key, source)| (format!("{} = {}", key, source.value), &source.origin))
+        .collect();
+    values.sort();
+    let width = values.iter().map(|(line, _)| line.len()).max().unwrap_or(0);
+    for (line, origin) in values {
+        println!("  {:width$}  # {}", line, origin, width = width);
+    }
+}
+
+// The file `boot edit` should open: --config, the project file or the user file
+fn editable_config_path(cli: &Cli) -> PathBuf {
+    if let Some(path) = &cli.config {
+        return PathBuf::from(path);
+    }
+    layers::find_project_config()
+        .or_else(layers::user_config_path)
+        .unwrap_or_else(|| exit_with_error("Could not find a config directory"))
+}
+
+// The log directory from [logging] dir, if logging is on
+fn log_dir(config: &CozyBootConfig, config_path: &Path) -> Result<Option<PathBuf>, String> {
+    let vars = Variables::new(&config.vars, &config.main.kern_root, config_path);
+    config.logging.resolve_dir(&vars)
+}
+
+// Start this boot's log file when [logging] dir is set
+fn open_boot_log(
+    config: &CozyBootConfig,
+    loaded: &LoadedConfig,
+    verbose: bool,
+) -> Result<Option<logging::SharedLog>, String> {
+    let Some(dir) = log_dir(config, &loaded.config_path())? else {
+        return Ok(None);
+    };
+    let log = logging::BootLog::create(&dir, loaded.profile.as_deref(), &config.logging)?;
+    if verbose {
+        println!("{}", format!("Logging to {}", log.path().display()).blue());
+    }
+    Ok(Some(Arc::new(Mutex::new(log))))
+}
+
+async fn run(cli: &Cli, run: &RunArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let loaded = load_config(cli);
+    let config = validated_config(&loaded).unwrap_or_else(|| std::process::exit(1));
+    let mut boot = resolve_boot(&config, &loaded.config_path(), run, cli.verbose)
+        .unwrap_or_else(|e| exit_with_error(e));
+    let backend = backend::select(&config.runtime).unwrap_or_else(|e| exit_with_error(e));
+
+    if cli.verbose {
+        println!(
+            "{}",
+            format!("Building {} command...", backend.name()).blue()
+        );
     }
 
-    if args.verbose {
-        println!("{}", format!("Reading configuration from {}", config_path.display()).blue());
+    if run.dry_run {
+        let plan = backend.plan(&boot).unwrap_or_else(|e| exit_with_error(e));
+        dry_run::print_invocation(&plan, run.format)?;
+        return Ok(());
     }
 
-    let config_content = fs::read_to_string(&config_path)?;
-    if args.verbose {
-        println!("Config content:\n{}", config_content);
+    let log = open_boot_log(&config, &loaded, cli.verbose).unwrap_or_else(|e| exit_with_error(e));
+    let vars = Variables::new(&config.vars, &config.main.kern_root, &loaded.config_path());
+
+    // A fresh user root comes from the template, before hooks get to fill it
+    provision_user_root(&config, &boot, &vars, log.as_ref()).unwrap_or_else(|e| exit_with_error(e));
+
+    // With --ephemeral everything from here on, hooks included, sees the copy
+    let ephemeral = run
+        .ephemeral
+        .then(|| start_ephemeral(&mut boot, run, cli.verbose, log.as_ref()));
+    let ephemeral = ephemeral.as_deref();
+
+    let mut env = hooks::boot_env(&boot, loaded.profile.as_deref());
+    if let Some(log) = log.as_ref().and_then(|log| log.lock().ok()) {
+        env.push((
+            "COZYBOOT_LOG".to_string(),
+            log.path().to_string_lossy().to_string(),
+        ));
     }
-    let config: CozyBootConfig = match toml::from_str(&config_content) {
-        Ok(config) => config,
-        Err(e) => {
-            eprintln!("Failed to parse config file: {}", e);
-            eprintln!("Config content:\n{}", config_content);
-            std::process::exit(1);
+
+    // Pre-boot hooks and [build] prepare what the boot needs; a failure aborts it
+    let prepared = match run_hooks(
+        hooks::Phase::PreBoot,
+        &config,
+        &vars,
+        &env,
+        cli.verbose,
+        log.as_ref(),
+    )
+    .await
+    {
+        Ok(()) => run_build(&config, &boot, &vars, &env, run, cli.verbose, log.as_ref())
+            .await
+            .map_err(|e| ("build failed", e)),
+        Err(e) => Err(("pre_boot hook failed", e)),
+    };
+    if let Err((reason, e)) = prepared {
+        env.push(("COZYBOOT_EXIT_CODE".to_string(), "1".to_string()));
+        env.push(("COZYBOOT_STOP_REASON".to_string(), reason.to_string()));
+        eprintln!("{}", format!("Error: {}", e).red());
+        if let Err(e) = run_hooks(
+            hooks::Phase::OnFailure,
+            &config,
+            &vars,
+            &env,
+            cli.verbose,
+            log.as_ref(),
+        )
+        .await
+        {
+            eprintln!("{}", format!("Warning: {}", e).yellow());
+        }
+        exit_boot(1, ephemeral);
+    }
+
+    // Catch an empty or half-built kernel before cozy-os trips over it
+    verify_kern_root(&config, &boot, cli.verbose).unwrap_or_else(|e| fail_boot(e, ephemeral));
+
+    // Find binaries CozyOS would refuse before it gets to them
+    if config.bin.scan_on_boot {
+        check_bins(&config, &boot, cli.verbose).unwrap_or_else(|e| fail_boot(e, ephemeral));
+    }
+
+    // Make sure the emulator is a version this config supports
+    match runtime::check_version(&boot.executable, &config.runtime) {
+        Ok(Some(version)) if cli.verbose => {
+            println!("{}", format!("cozy-os version {}", version).blue());
         }
+        Ok(_) => {}
+        Err(e) => fail_boot(e, ephemeral),
+    }
+
+    // An ephemeral copy is thrown away anyway, so there is nothing to protect
+    if config.main.snapshot_on_boot && ephemeral.is_none() {
+        snapshot_before_boot(&config, &boot, &vars, cli.verbose, log.as_ref())
+            .unwrap_or_else(|e| exit_with_error(e));
+    }
+
+    let options = supervisor::RunOptions {
+        timeout: run
+            .timeout
+            .or(config.runtime.timeout)
+            .map(HumanDuration::as_duration),
+        expect: expect::expectations(&config).unwrap_or_else(|e| fail_boot(e, ephemeral)),
+        log: log.clone(),
     };
 
-    // Validate OS path
-    let expanded_kern_root = expand_variables(&config.main.kern_root);
-    if !Path::new(&expanded_kern_root).exists() {
-        eprintln!("{}", format!("Error: OS path '{}' does not exist (expanded from '{}')", 
-            expanded_kern_root, config.main.kern_root).red());
-        std::process::exit(1);
+    // Run the boot, restarting it as `[supervisor] restart` asks
+    let policy = &config.supervisor;
+    if let Some(ephemeral) = ephemeral {
+        ephemeral.supervising.store(true, Ordering::SeqCst);
+    }
+    let summary =
+        supervisor::supervise(
+            backend.as_ref(),
+            &boot,
+            policy,
+            &options,
+            |event| match event {
+                supervisor::Event::Started { attempt } if attempt > 1 || cli.verbose => {
+                    println!(
+                        "{}",
+                        format!("Starting CozyOS (attempt {})...", attempt).green()
+                    );
+                }
+                supervisor::Event::Started { .. } => {}
+                supervisor::Event::Finished(attempt) if attempt.succeeded() => {
+                    if cli.verbose || policy.restart != supervisor::RestartPolicy::Never {
+                        let ended = match attempt.stopped {
+                            Some(reason) => format!("stopped ({})", reason),
+                            None => attempt.outcome.to_string(),
+                        };
+                        println!(
+                            "{}",
+                            format!(
+                                "CozyOS {} after {:.1}s",
+                                ended,
+                                attempt.runtime.as_secs_f64()
+                            )
+                            .blue()
+                        );
+                    }
+                }
+                supervisor::Event::Finished(attempt) => {
+                    eprintln!(
+                        "{}",
+                        format!(
+                            "Error: CozyOS {} after {:.1}s",
+                            attempt.outcome,
+                            attempt.runtime.as_secs_f64()
+                        )
+                        .red()
+                    );
+                }
+                supervisor::Event::Restarting { attempt, delay } => {
+                    println!(
+                        "{}",
+                        format!(
+                            "Restarting in {} (attempt {} of {})...",
+                            HumanDuration::from(delay),
+                            attempt,
+                            policy.max_restarts + 1
+                        )
+                        .yellow()
+                    );
+                }
+                supervisor::Event::GivingUp(reason) => {
+                    eprintln!(
+                        "{}",
+                        format!("Error: giving up on CozyOS: {}", reason).red()
+                    );
+                }
+                supervisor::Event::Forwarded { signal } => {
+                    let name = supervisor::signal_name(signal)
+                        .map_or_else(|| format!("signal {}", signal), str::to_string);
+                    println!(
+                        "{}",
+                        format!("Received {}, passing it on to CozyOS...", name).yellow()
+                    );
+                }
+                supervisor::Event::Killing { grace_period } => {
+                    eprintln!(
+                        "{}",
+                        format!(
+                            "Error: CozyOS did not exit within {}, sending SIGKILL",
+                            HumanDuration::from(grace_period)
+                        )
+                        .red()
+                    );
+                }
+                supervisor::Event::Matched {
+                    pattern,
+                    action,
+                    after,
+                } => {
+                    if cli.verbose || action != ExpectAction::Ready {
+                        let line = format!(
+                            "Matched '{}' ({}) after {:.1}s",
+                            pattern,
+                            action,
+                            after.as_secs_f64()
+                        );
+                        match action {
+                            ExpectAction::Fail => eprintln!("{}", format!("Error: {}", line).red()),
+                            _ => println!("{}", line.green()),
+                        }
+                    }
+                }
+                supervisor::Event::TimedOut { timeout } => {
+                    eprintln!(
+                        "{}",
+                        format!(
+                            "Error: CozyOS is still running after {}, stopping it",
+                            HumanDuration::from(timeout)
+                        )
+                        .red()
+                    );
+                }
+                supervisor::Event::Missed { pattern, within } => {
+                    eprintln!(
+                        "{}",
+                        format!(
+                            "Error: CozyOS did not print '{}' within {}, stopping it",
+                            pattern,
+                            HumanDuration::from(within)
+                        )
+                        .red()
+                    );
+                }
+            },
+        )
+        .await;
+    if let Some(ephemeral) = ephemeral {
+        ephemeral.supervising.store(false, Ordering::SeqCst);
     }
 
-    if args.verbose {
-        println!("{}", "Building CozyOS command...".blue());
+    if policy.restart != supervisor::RestartPolicy::Never {
+        print_attempts(&summary);
     }
 
-    // Build command arguments
-    let mut command = Command::new("cozy-os");
+    let code = summary.exit_code();
+    env.push(("COZYBOOT_EXIT_CODE".to_string(), code.to_string()));
+    env.push(("COZYBOOT_STOP_REASON".to_string(), summary.stop.to_string()));
+    env.push((
+        "COZYBOOT_ATTEMPTS".to_string(),
+        summary.attempts.len().to_string(),
+    ));
 
-    // Set kernel and user roots with expanded paths
-    command.arg("--kern-root").arg(expand_variables(&config.main.kern_root))
-           .arg("--user-root").arg(expand_variables(&config.main.user_root));
+    // Hook failures after the boot are reported but keep the boot's exit code
+    let mut phases = vec![hooks::Phase::PostBoot];
+    if code != 0 {
+        phases.push(hooks::Phase::OnFailure);
+    }
+    for phase in phases {
+        if let Err(e) = run_hooks(phase, &config, &vars, &env, cli.verbose, log.as_ref()).await {
+            eprintln!("{}", format!("Warning: {}", e).yellow());
+        }
+    }
+    if let Some(ephemeral) = ephemeral {
+        ephemeral.finish(code);
+    }
 
-    // Add boot arguments
-    for (key, value) in config.bootargs {
-        command.arg(format!("--{}={}", key, value));
+    if let Some(log) = log.as_ref().and_then(|log| log.lock().ok()) {
+        if code != 0 || cli.verbose {
+            println!(
+                "{}",
+                format!(
+                    "Boot log: {} (boot logs --id {})",
+                    log.path().display(),
+                    log.id()
+                )
+                .blue()
+            );
+        }
+    }
+    if code != 0 {
+        std::process::exit(code);
     }
 
-    // Configure binary settings
-    if let Some(allow_32bit) = config.bin.allow_32bit {
-        command.arg("--allow-32bit").arg(allow_32bit.to_string());
+    if cli.verbose {
+        println!("{}", "CozyOS shut down cleanly".green());
     }
-    if let Some(allow_universal) = config.bin.allow_universal {
-        command.arg("--allow-universal").arg(allow_universal.to_string());
+
+    Ok(())
+}
+
+// The --ephemeral copy of user_root, shared with the task that cleans it up
+// when boot is interrupted outside supervision
+struct Ephemeral {
+    root: EphemeralRoot,
+    keep_on_failure: bool,
+    /// While set, shutdown signals are the supervisor's to forward to CozyOS
+    supervising: AtomicBool,
+}
+
+impl Ephemeral {
+    // Delete the copy, unless the boot failed and --keep-on-failure asks to keep it
+    fn finish(&self, code: i32) {
+        if code != 0 && self.keep_on_failure {
+            match self.root.keep() {
+                Ok(kept) => println!(
+                    "{}",
+                    format!("Kept the ephemeral user root at {}", kept.display()).yellow()
+                ),
+                Err(e) => eprintln!("{}", format!("Warning: {}", e).yellow()),
+            }
+        } else if let Err(e) = self.root.remove() {
+            eprintln!("{}", format!("Warning: {}", e).yellow());
+        }
     }
-    if let Some(allow_64bit) = config.bin.allow_64bit {
-        command.arg("--allow-64bit").arg(allow_64bit.to_string());
+}
+
+// Copy user_root for --ephemeral and point the boot at the copy
+fn start_ephemeral(
+    boot: &mut ResolvedBoot,
+    run: &RunArgs,
+    verbose: bool,
+    log: Option<&logging::SharedLog>,
+) -> Arc<Ephemeral> {
+    // Installed before copying, so Ctrl-C from here on cannot leave the copy behind
+    let mut signals = supervisor::ShutdownSignals::install().unwrap_or_else(|e| exit_with_error(e));
+    let root =
+        EphemeralRoot::create(Path::new(&boot.user_root)).unwrap_or_else(|e| exit_with_error(e));
+    let message = format!(
+        "Using ephemeral user root {} (copied from {})",
+        root.path().display(),
+        boot.user_root
+    );
+    logging::write_shared(log, "boot", &message);
+    if verbose {
+        println!("{}", message.blue());
     }
 
-    // Add boot string if provided
-    if let Some(boot_string) = args.boot_string {
-        command.arg("--boot-string").arg(boot_string);
+    boot.user_root = root.path().to_string_lossy().to_string();
+    let ephemeral = Arc::new(Ephemeral {
+        root,
+        keep_on_failure: run.keep_on_failure,
+        supervising: AtomicBool::new(false),
+    });
+    let guard = Arc::clone(&ephemeral);
+    tokio::spawn(async move {
+        loop {
+            let signal = signals.recv().await;
+            if !guard.supervising.load(Ordering::SeqCst) {
+                let name = supervisor::signal_name(signal)
+                    .map_or_else(|| format!("signal {}", signal), str::to_string);
+                eprintln!("{}", format!("Error: interrupted by {}", name).red());
+                guard.finish(128 + signal);
+                std::process::exit(128 + signal);
+            }
+        }
+    });
+    ephemeral
+}
+
+// Exit with `code`, first cleaning up the --ephemeral copy
+fn exit_boot(code: i32, ephemeral: Option<&Ephemeral>) -> ! {
+    if let Some(ephemeral) = ephemeral {
+        ephemeral.finish(code);
     }
+    std::process::exit(code);
+}
 
-    // Add debug mode if enabled
-    if args.debug {
-        command.arg("--debug");
-        if args.verbose {
-            println!("{}", "Debug mode enabled".blue());
+// Print an error in red and exit with status 1, cleaning up like exit_boot
+fn fail_boot(message: impl Display, ephemeral: Option<&Ephemeral>) -> ! {
+    eprintln!("{}", format!("Error: {}", message).red());
+    exit_boot(1, ephemeral);
+}
+
+// Run one phase of [hooks], reporting each command and recording it in the boot log
+async fn run_hooks(
+    phase: hooks::Phase,
+    config: &CozyBootConfig,
+    vars: &Variables,
+    env: &[(String, String)],
+    verbose: bool,
+    log: Option<&logging::SharedLog>,
+) -> Result<(), String> {
+    hooks::run(phase, &config.hooks, vars, env, |event| match event {
+        hooks::HookEvent::Started { phase, command } => {
+            if verbose {
+                println!("{}", format!("Running {} hook: {}", phase, command).blue());
+            }
+            logging::write_shared(log, "hook", &format!("{}: {}", phase, command));
+        }
+        hooks::HookEvent::Finished {
+            phase,
+            command,
+            result,
+            runtime,
+        } => {
+            if verbose {
+                println!(
+                    "{}",
+                    format!("{} hook '{}' {}", phase, command, result).blue()
+                );
+            }
+            logging::write_shared(
+                log,
+                "hook",
+                &format!("{}: {} after {:.1}s", phase, result, runtime.as_secs_f64()),
+            );
         }
+    })
+    .await
+}
+
+// Rebuild the kernel with [build] when its output is missing or out of date
+async fn run_build(
+    config: &CozyBootConfig,
+    boot: &ResolvedBoot,
+    vars: &Variables,
+    env: &[(String, String)],
+    run: &RunArgs,
+    verbose: bool,
+    log: Option<&logging::SharedLog>,
+) -> Result<(), String> {
+    let Some(build) = &config.build else {
+        return Ok(());
+    };
+    if run.no_build {
+        if verbose {
+            println!("{}", "Skipping [build] (--no-build)".blue());
+        }
+        return Ok(());
     }
 
-    if args.verbose {
-        println!("{}", "Starting CozyOS...".green());
+    let plan = build.resolve(vars, &boot.kern_root)?;
+    let reason = if run.force_build {
+        "--force-build".to_string()
+    } else {
+        let staleness = plan.staleness()?;
+        if !staleness.needs_build() {
+            if verbose {
+                println!(
+                    "{}",
+                    format!("{} is up to date, not building", plan.output.display()).blue()
+                );
+            }
+            return Ok(());
+        }
+        staleness.to_string()
+    };
+
+    println!(
+        "{}",
+        format!("Building kernel ({}): {}", reason, plan.command).green()
+    );
+    logging::write_shared(log, "build", &format!("{} ({})", plan.command, reason));
+    let started = std::time::Instant::now();
+    let result = hooks::run_shell(&plan.command, env, None, plan.timeout).await;
+    logging::write_shared(
+        log,
+        "build",
+        &format!("{} after {:.1}s", result, started.elapsed().as_secs_f64()),
+    );
+    if !result.success() {
+        return Err(format!("build command '{}' {}", plan.command, result));
+    }
+    if !plan.output.exists() {
+        return Err(format!(
+            "build command '{}' succeeded but did not create {}",
+            plan.command,
+            plan.output.display()
+        ));
+    }
+    if verbose {
+        println!(
+            "{}",
+            format!(
+                "Built {} in {:.1}s",
+                plan.output.display(),
+                started.elapsed().as_secs_f64()
+            )
+            .blue()
+        );
+    }
+    // The build succeeded; without a stamp the next boot only builds again
+    if let Err(e) = plan.record() {
+        eprintln!(
+            "{}",
+            format!("Warning: could not record the build inputs: {}", e).yellow()
+        );
     }
+    Ok(())
+}
 
-    // Execute the command
-    let status = command.status()?;
+// Create a missing user_root by copying [main] user_root_template
+fn provision_user_root(
+    config: &CozyBootConfig,
+    boot: &ResolvedBoot,
+    vars: &Variables,
+    log: Option<&logging::SharedLog>,
+) -> Result<(), String> {
+    let user_root = Path::new(&boot.user_root);
+    let Some(template) = &config.main.user_root_template else {
+        return Ok(());
+    };
+    if user_root.exists() {
+        return Ok(());
+    }
+
+    let template = vars
+        .expand(template)
+        .map_err(|e| format!("failed to expand main.user_root_template: {}", e))?;
+    let copied = user_root::provision(user_root, Path::new(&template), &config.main.user_skeleton)?;
+    let message = format!(
+        "Created user root {} from {} ({} files)",
+        user_root.display(),
+        template,
+        copied
+    );
+    logging::write_shared(log, "boot", &message);
+    println!("{}", message.green());
+    Ok(())
+}
+
+// Take an automatic snapshot of user_root and prune the old ones
+fn snapshot_before_boot(
+    config: &CozyBootConfig,
+    boot: &ResolvedBoot,
+    vars: &Variables,
+    verbose: bool,
+    log: Option<&logging::SharedLog>,
+) -> Result<(), String> {
+    let user_root = Path::new(&boot.user_root);
+    let dir = snapshot::resolve_dir(config.main.snapshot_dir.as_deref(), vars, user_root)?;
+    let (entry, stats) = snapshot::create(&dir, user_root, None, true)?;
+    let message = format!(
+        "Snapshot {}: {} file(s), {} hardlinked, {} copied",
+        entry.id,
+        stats.files,
+        stats.linked,
+        logging::ByteSize(stats.copied_bytes)
+    );
+    logging::write_shared(log, "snapshot", &message);
+    if verbose {
+        println!("{}", message.blue());
+    }
 
+    for id in snapshot::prune(&dir, config.main.snapshot_keep)? {
+        logging::write_shared(log, "snapshot", &format!("pruned {}", id));
+        if verbose {
+            println!("{}", format!("Deleted old snapshot {}", id).blue());
+        }
+    }
+    Ok(())
+}
+
+// Check kern_root against [main] required_files and its manifest.toml
+fn verify_kern_root(
+    config: &CozyBootConfig,
+    boot: &ResolvedBoot,
+    verbose: bool,
+) -> Result<(), String> {
+    let kern_root = Path::new(&boot.kern_root);
+    let files = manifest::required_files(&config.main.required_files, kern_root)?;
+    if files.is_empty() {
+        return Ok(());
+    }
+
+    let problems = manifest::verify(kern_root, &files);
+    if problems.is_empty() {
+        if verbose {
+            println!(
+                "{}",
+                format!(
+                    "Verified {} kernel file(s) in {}",
+                    files.len(),
+                    kern_root.display()
+                )
+                .blue()
+            );
+        }
+        return Ok(());
+    }
+    let lines: Vec<String> = problems
+        .iter()
+        .map(|problem| format!("  {}", problem))
+        .collect();
+    Err(format!(
+        "kern_root '{}' is incomplete or corrupt ({} of {} required files):\n{}",
+        kern_root.display(),
+        problems.len(),
+        files.len(),
+        lines.join("\n")
+    ))
+}
+
+// Scan user_root for binaries and pair each one [bin] rejects with the reason
+fn scan_user_root<'a>(
+    config: &CozyBootConfig,
+    report: &'a binscan::ScanReport,
+) -> Vec<(&'a binscan::Binary, String)> {
+    for error in &report.errors {
+        eprintln!("{}", format!("Warning: could not scan {}", error).yellow());
+    }
+    report
+        .binaries
+        .iter()
+        .filter_map(|binary| binary.rejection(&config.bin).map(|reason| (binary, reason)))
+        .collect()
+}
+
+// Check user_root against the [bin] policy before booting
+fn check_bins(config: &CozyBootConfig, boot: &ResolvedBoot, verbose: bool) -> Result<(), String> {
+    let report = binscan::scan(Path::new(&boot.user_root));
+    let rejected = scan_user_root(config, &report);
+    if rejected.is_empty() {
+        if verbose {
+            println!(
+                "{}",
+                format!(
+                    "Scanned {} file(s) in {}: {} binaries, all allowed by [bin]",
+                    report.files,
+                    boot.user_root,
+                    report.binaries.len()
+                )
+                .blue()
+            );
+        }
+        return Ok(());
+    }
+    let lines: Vec<String> = rejected
+        .iter()
+        .map(|(binary, reason)| format!("  {}: {}; {}", binary.path.display(), binary, reason))
+        .collect();
+    Err(format!(
+        "user root '{}' contains {} binaries that [bin] rejects:\n{}",
+        boot.user_root,
+        rejected.len(),
+        lines.join("\n")
+    ))
+}
+
+// Print one line per supervised attempt and why supervision stopped
+fn print_attempts(summary: &supervisor::Summary) {
+    println!(
+        "{}",
+        format!("{} attempt(s), {}:", summary.attempts.len(), summary.stop).blue()
+    );
+    for attempt in &summary.attempts {
+        let line = format!(
+            "  #{:<3} {:>8.1}s  {}",
+            attempt.number,
+            attempt.runtime.as_secs_f64(),
+            attempt.outcome
+        );
+        if attempt.succeeded() {
+            println!("{}", line.green());
+        } else {
+            println!("{}", line.red());
+        }
+    }
+}
+
+fn init(args: &InitArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let path = match &args.path {
+        Some(path) if path.is_dir() => path.join(layers::PROJECT_CONFIG_NAME),
+        Some(path) => path.clone(),
+        None => layers::user_config_path()
+            .unwrap_or_else(|| exit_with_error("Could not find a config directory")),
+    };
+
+    if path.exists() && !args.force {
+        exit_with_error(format!(
+            "Configuration file already exists at {} (use --force to overwrite)",
+            path.display()
+        ));
+    }
+
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    std::fs::write(&path, args.template.contents())?;
+
+    println!(
+        "{}",
+        format!("Wrote configuration to {}", path.display()).green()
+    );
+    Ok(())
+}
+
+// Validate the config and report the result; true if there were no errors
+fn check(cli: &Cli) -> bool {
+    let loaded =
+        match LoadedConfig::load(cli.config.as_deref().map(Path::new), cli.profile.as_deref()) {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                diagnostics::report(&[e]);
+                return false;
+            }
+        };
+
+    match validated_config(&loaded) {
+        Some(config) => match resolve_boot(&config, &loaded.config_path(), &cli.run, cli.verbose)
+            .and_then(|boot| {
+                // The kernel may not exist until [build] has run
+                if config.build.is_none() {
+                    verify_kern_root(&config, &boot, cli.verbose)?;
+                }
+                backend::select(&config.runtime)?.plan(&boot)
+            }) {
+            Ok(_) => true,
+            Err(e) => {
+                diagnostics::report(&[Diagnostic::error(e)]);
+                false
+            }
+        },
+        None => false,
+    }
+}
+
+fn show(cli: &Cli) -> Result<(), Box<dyn std::error::Error>> {
+    let loaded = load_config(cli);
+    if let Some(profile) = &loaded.profile {
+        println!("# profile: {}", profile);
+    }
+    print!("{}", toml::to_string_pretty(&loaded.merged.table)?);
+    Ok(())
+}
+
+fn edit(cli: &Cli) -> Result<(), Box<dyn std::error::Error>> {
+    let path = editable_config_path(cli);
+    if !path.exists() {
+        exit_with_error(format!(
+            "Configuration file not found at {} (run `boot init` first)",
+            path.display()
+        ));
+    }
+
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string());
+
+    // The editor may carry its own arguments, e.g. EDITOR="code --wait"
+    let mut words = editor.split_whitespace();
+    let program = words.next().unwrap_or("vi");
+    let status = Command::new(program)
+        .args(words)
+        .arg(&path)
+        .status()
+        .map_err(|e| format!("failed to start editor '{}': {}", editor, e))?;
     if !status.success() {
-        eprintln!("{}", "Error: CozyOS failed to start".red());
-        std::process::exit(status.code().unwrap_or(1));
+        exit_with_error(format!("editor '{}' exited with {}", editor, status));
+    }
+
+    if check(cli) {
+        println!("{}", format!("{} is valid", path.display()).green());
+        Ok(())
+    } else {
+        exit_with_error(format!("{} is not valid after editing", path.display()))
+    }
+}
+
+fn logs(cli: &Cli, args: &LogsArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let loaded = load_config(cli);
+    // The last boot's log matters most when kern_root or user_root is broken,
+    // so only the config has to deserialize; other problems are only warnings
+    let validation = validate::validate(&loaded);
+    let Some(config) = validation.config else {
+        exit_with_diagnostics(&validation.diagnostics);
+    };
+    let warnings: Vec<Diagnostic> = validation
+        .diagnostics
+        .into_iter()
+        .map(|diagnostic| Diagnostic {
+            severity: diagnostics::Severity::Warning,
+            ..diagnostic
+        })
+        .collect();
+    diagnostics::report(&warnings);
+    let dir = log_dir(&config, &loaded.config_path())
+        .unwrap_or_else(|e| exit_with_error(e))
+        .unwrap_or_else(|| exit_with_error("no log directory configured; set [logging] dir"));
+    let entries = logging::list(&dir).unwrap_or_else(|e| exit_with_error(e));
+
+    let id = match (&args.id, args.last) {
+        (Some(id), _) => id.clone(),
+        (None, true) => match entries.last() {
+            Some(entry) => entry.id.clone(),
+            None => exit_with_error(format!("no boot logs in {}", dir.display())),
+        },
+        (None, false) => {
+            for entry in &entries {
+                println!(
+                    "{}  {:>10}  {} file(s)",
+                    entry.id,
+                    logging::ByteSize(entry.size).to_string(),
+                    entry.files.len()
+                );
+            }
+            return Ok(());
+        }
+    };
+
+    print!(
+        "{}",
+        logging::read(&dir, &id).unwrap_or_else(|e| exit_with_error(e))
+    );
+    Ok(())
+}
+
+fn scan_bins(cli: &Cli, args: &ScanBinsArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let loaded = load_config(cli);
+    let config = validated_config(&loaded).unwrap_or_else(|| std::process::exit(1));
+    let boot = resolve_boot(&config, &loaded.config_path(), &cli.run, cli.verbose)
+        .unwrap_or_else(|e| exit_with_error(e));
+
+    let report = binscan::scan(Path::new(&boot.user_root));
+    let rejected = scan_user_root(&config, &report);
+    let width = report
+        .binaries
+        .iter()
+        .map(|binary| binary.path.as_os_str().len())
+        .max()
+        .unwrap_or(0);
+    for binary in &report.binaries {
+        let line = format!(
+            "{:width$}  {}",
+            binary.path.display().to_string(),
+            binary,
+            width = width
+        );
+        match binary.rejection(&config.bin) {
+            Some(reason) => println!("{}", format!("{}  rejected: {}", line, reason).red()),
+            None if !args.rejected => println!("{}", line),
+            None => {}
+        }
     }
 
-    if args.verbose {
-        println!("{}", "CozyOS started successfully!".green());
+    let summary = format!(
+        "Scanned {} file(s) in {}: {} binaries, {} rejected by [bin]",
+        report.files,
+        boot.user_root,
+        report.binaries.len(),
+        rejected.len()
+    );
+    if rejected.is_empty() {
+        println!("{}", summary.green());
+        Ok(())
+    } else {
+        eprintln!("{}", format!("Error: {}", summary).red());
+        std::process::exit(1);
     }
+}
+
+fn snapshot(cli: &Cli, action: &SnapshotCommand) -> Result<(), Box<dyn std::error::Error>> {
+    let loaded = load_config(cli);
+    // Restoring is how a broken user root gets fixed, so only the config has
+    // to deserialize; the checks on user_root itself would get in the way
+    let validation = validate::validate(&loaded);
+    let Some(config) = validation.config else {
+        exit_with_diagnostics(&validation.diagnostics);
+    };
+    let vars = Variables::new(&config.vars, &config.main.kern_root, &loaded.config_path());
+    let user_root =
+        PathBuf::from(vars.expand(&config.main.user_root).unwrap_or_else(|e| {
+            exit_with_error(format!("failed to expand main.user_root: {}", e))
+        }));
+    let dir = snapshot::resolve_dir(config.main.snapshot_dir.as_deref(), &vars, &user_root)
+        .unwrap_or_else(|e| exit_with_error(e));
 
+    match action {
+        SnapshotCommand::Create { name } => {
+            let (entry, stats) = snapshot::create(&dir, &user_root, name.as_deref(), false)
+                .unwrap_or_else(|e| exit_with_error(e));
+            println!(
+                "{}",
+                format!(
+                    "Created snapshot {} ({} file(s), {} hardlinked, {} copied)",
+                    entry.id,
+                    stats.files,
+                    stats.linked,
+                    logging::ByteSize(stats.copied_bytes)
+                )
+                .green()
+            );
+        }
+        SnapshotCommand::List => {
+            let entries = snapshot::list(&dir).unwrap_or_else(|e| exit_with_error(e));
+            if entries.is_empty() {
+                println!("No snapshots in {}", dir.display());
+            }
+            let width = entries
+                .iter()
+                .map(|entry| entry.id.len())
+                .max()
+                .unwrap_or(0);
+            for entry in &entries {
+                let (date, time) = logging::utc_parts(entry.created);
+                let (files, size) = snapshot::usage(&entry.path);
+                println!(
+                    "{:width$}  {} {}  {:>6} file(s)  {:>10}",
+                    entry.id,
+                    date,
+                    &time[..8],
+                    files,
+                    logging::ByteSize(size).to_string(),
+                    width = width
+                );
+            }
+        }
+        SnapshotCommand::Restore { id } => {
+            let copied =
+                snapshot::restore(&dir, id, &user_root).unwrap_or_else(|e| exit_with_error(e));
+            println!(
+                "{}",
+                format!(
+                    "Restored {} from snapshot {} ({} file(s))",
+                    user_root.display(),
+                    id,
+                    copied
+                )
+                .green()
+            );
+        }
+        SnapshotCommand::Delete { id } => {
+            snapshot::delete(&dir, id).unwrap_or_else(|e| exit_with_error(e));
+            println!("{}", format!("Deleted snapshot {}", id).green());
+        }
+    }
     Ok(())
 }
+
+// The run options given before a subcommand would otherwise be dropped
+// silently, turning `boot --dry-run run` into a real boot
+fn reject_run_args_before_subcommand(matches: &ArgMatches) {
+    let Some((name, _)) = matches.subcommand() else {
+        return;
+    };
+    let run_args = RunArgs::augment_args(clap::Command::new("run"));
+    let given: Vec<String> = run_args
+        .get_arguments()
+        .filter(|arg| matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine))
+        .map(|arg| format!("'--{}'", arg.get_long().unwrap_or(arg.get_id().as_str())))
+        .collect();
+    if given.is_empty() {
+        return;
+    }
+    let hint = if name == "run" {
+        " (give run options after 'run')"
+    } else {
+        ""
+    };
+    Cli::command()
+        .error(
+            clap::error::ErrorKind::ArgumentConflict,
+            format!(
+                "the subcommand '{}' cannot be used with {}{}",
+                name,
+                given.join(", "),
+                hint
+            ),
+        )
+        .exit();
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let matches = Cli::command().get_matches();
+    reject_run_args_before_subcommand(&matches);
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+
+    match &cli.command {
+        None => run(&cli, &cli.run).await,
+        Some(Commands::Run(run_args)) => run(&cli, run_args).await,
+        Some(Commands::Init(init_args)) => init(init_args),
+        Some(Commands::Check) => {
+            if !check(&cli) {
+                std::process::exit(1);
+            }
+            println!("{}", "Configuration OK".green());
+            Ok(())
+        }
+        Some(Commands::Show) => show(&cli),
+        Some(Commands::Edit) => edit(&cli),
+        Some(Commands::Logs(logs_args)) => logs(&cli, logs_args),
+        Some(Commands::ScanBins(scan_args)) => scan_bins(&cli, scan_args),
+        Some(Commands::Snapshot { action }) => snapshot(&cli, action),
+        Some(Commands::Config {
+            action: ConfigCommand::Sources,
+        }) => {
+            print_sources(&load_config(&cli));
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,310 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::path::{Component, Path};
+
+use crate::digest;
+use crate::logging::ByteSize;
+
+/// File in `kern_root` listing what a complete kernel contains
+pub const MANIFEST_FILE: &str = "manifest.toml";
+
+/// `kern_root/manifest.toml`: `files = ["kernel.img", { path = "...", sha256 = "..." }]`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    #[serde(default)]
+    pub files: Vec<RequiredFile>,
+}
+
+/// A file the kernel needs, relative to `kern_root`, either as a plain path
+/// (which may also be a directory) or with the size and checksum it must have
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum RequiredFile {
+    Detailed(FileSpec),
+    Path(String),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileSpec {
+    pub path: String,
+    /// Exact size the file must have
+    pub size: Option<ByteSize>,
+    /// Smallest acceptable size, to catch empty or truncated files
+    pub min_size: Option<ByteSize>,
+    /// Expected SHA-256 of the contents, as hex
+    pub sha256: Option<String>,
+}
+
+impl RequiredFile {
+    pub fn path(&self) -> &str {
+        match self {
+            RequiredFile::Detailed(spec) => &spec.path,
+            RequiredFile::Path(path) => path,
+        }
+    }
+
+    fn spec(&self) -> Option<&FileSpec> {
+        match self {
+            RequiredFile::Detailed(spec) => Some(spec),
+            RequiredFile::Path(_) => None,
+        }
+    }
+
+    /// Check the entry itself, before looking at any file
+    pub fn check(&self) -> Result<(), String> {
+        let path = Path::new(self.path());
+        if self.path().trim().is_empty() {
+            return Err("required file has an empty path".to_string());
+        }
+        if path.is_absolute()
+            || path
+                .components()
+                .any(|component| component == Component::ParentDir)
+        {
+            return Err(format!(
+                "required file '{}' must be a path inside kern_root",
+                self.path()
+            ));
+        }
+        let Some(spec) = self.spec() else {
+            return Ok(());
+        };
+        if let Some(sha256) = &spec.sha256 {
+            if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(format!("sha256 of '{}' is not 64 hex digits", spec.path));
+            }
+        }
+        if let (Some(size), Some(min_size)) = (spec.size, spec.min_size) {
+            if size < min_size {
+                return Err(format!(
+                    "size {} of '{}' is below its min_size {}",
+                    size, spec.path, min_size
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What is wrong with one required file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    Missing,
+    NotAFile,
+    WrongSize { expected: u64, actual: u64 },
+    TooSmall { min: u64, actual: u64 },
+    Corrupt { expected: String, actual: String },
+    Unreadable(String),
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::Missing => write!(f, "missing"),
+            Problem::NotAFile => write!(f, "not a regular file"),
+            Problem::WrongSize { expected, actual } => {
+                write!(f, "is {} bytes, expected {}", actual, expected)
+            }
+            Problem::TooSmall { min, actual } => {
+                write!(f, "is {} bytes, expected at least {}", actual, min)
+            }
+            Problem::Corrupt { expected, actual } => {
+                write!(f, "SHA-256 is {}, expected {}", actual, expected)
+            }
+            Problem::Unreadable(e) => write!(f, "cannot be read: {}", e),
+        }
+    }
+}
+
+/// A required file that failed verification
+#[derive(Debug, Clone)]
+pub struct FileProblem {
+    /// As written in the manifest, relative to `kern_root`
+    pub path: String,
+    pub problem: Problem,
+}
+
+impl fmt::Display for FileProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.problem)
+    }
+}
+
+/// Read `kern_root/manifest.toml`, if there is one
+pub fn load(kern_root: &Path) -> Result<Option<Manifest>, String> {
+    let path = kern_root.join(MANIFEST_FILE);
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("failed to read {}: {}", path.display(), e)),
+    };
+    let manifest: Manifest =
+        toml::from_str(&content).map_err(|e| format!("invalid {}: {}", path.display(), e))?;
+    for file in &manifest.files {
+        file.check()
+            .map_err(|e| format!("invalid {}: {}", path.display(), e))?;
+    }
+    Ok(Some(manifest))
+}
+
+/// Every file a boot needs: `[main] required_files` followed by the manifest's
+pub fn required_files(
+    configured: &[RequiredFile],
+    kern_root: &Path,
+) -> Result<Vec<RequiredFile>, String> {
+    let mut files = configured.to_vec();
+    if let Some(manifest) = load(kern_root)? {
+        files.extend(manifest.files);
+    }
+    Ok(files)
+}
+
+/// Check that each file exists under `kern_root` with the right size and
+/// checksum, returning every problem found
+pub fn verify(kern_root: &Path, files: &[RequiredFile]) -> Vec<FileProblem> {
+    let mut problems = Vec::new();
+    for file in files {
+        if let Err(problem) = verify_one(&kern_root.join(file.path()), file.spec()) {
+            problems.push(FileProblem {
+                path: file.path().to_string(),
+                problem,
+            });
+        }
+    }
+    problems
+}
+
+fn verify_one(path: &Path, spec: Option<&FileSpec>) -> Result<(), Problem> {
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Err(Problem::Missing),
+        Err(e) => return Err(Problem::Unreadable(e.to_string())),
+    };
+    // A plain path may name a directory, such as a modules folder
+    let Some(spec) = spec else { return Ok(()) };
+    if !metadata.is_file() {
+        return Err(Problem::NotAFile);
+    }
+
+    let actual = metadata.len();
+    if let Some(ByteSize(expected)) = spec.size.filter(|size| size.0 != actual) {
+        return Err(Problem::WrongSize { expected, actual });
+    }
+    if let Some(ByteSize(min)) = spec.min_size.filter(|min| actual < min.0) {
+        return Err(Problem::TooSmall { min, actual });
+    }
+    // Hash last: it reads the whole file
+    if let Some(expected) = &spec.sha256 {
+        let actual = digest::sha256_file(path).map_err(|e| Problem::Unreadable(e.to_string()))?;
+        if !actual.eq_ignore_ascii_case(expected) {
+            return Err(Problem::Corrupt {
+                expected: expected.to_ascii_lowercase(),
+                actual,
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    // SHA-256 of "kernel.img", the contents with_files gives kernel.img
+    const KERNEL_SHA256: &str = "e147223559eaf37c54120437dc56457946cadc19572eb4a73802e7f2fc4610cc";
+
+    fn manifest(content: &str) -> Manifest {
+        toml::from_str(content).unwrap()
+    }
+
+    fn problems(kern_root: &Path, content: &str) -> Vec<String> {
+        verify(kern_root, &manifest(content).files)
+            .iter()
+            .map(ToString::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn accepts_files_that_match() {
+        let tmp = TempDir::new("manifest-ok").with_files(&["kernel.img", "modules/a.ko"]);
+        let content = format!(
+            r#"files = ["modules", {{ path = "kernel.img", size = 10, min_size = 4, sha256 = "{}" }}]"#,
+            KERNEL_SHA256.to_uppercase()
+        );
+        assert_eq!(problems(tmp.path(), &content), Vec::<String>::new());
+    }
+
+    #[test]
+    fn reports_every_problem() {
+        let tmp = TempDir::new("manifest-bad").with_files(&["kernel.img", "modules/a.ko"]);
+        let content = format!(
+            r#"files = [
+                "initrd.img",
+                {{ path = "modules", min_size = 1 }},
+                {{ path = "kernel.img", size = "1KiB" }},
+                {{ path = "kernel.img", min_size = 11 }},
+                {{ path = "kernel.img", sha256 = "{}" }},
+            ]"#,
+            "0".repeat(64)
+        );
+        assert_eq!(
+            problems(tmp.path(), &content),
+            [
+                "initrd.img: missing".to_string(),
+                "modules: not a regular file".to_string(),
+                "kernel.img: is 10 bytes, expected 1024".to_string(),
+                "kernel.img: is 10 bytes, expected at least 11".to_string(),
+                format!(
+                    "kernel.img: SHA-256 is {}, expected {}",
+                    KERNEL_SHA256,
+                    "0".repeat(64)
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn checks_entries_before_files() {
+        for (entry, error) in [
+            (r#""""#, "required file has an empty path"),
+            (r#""/etc/passwd""#, "must be a path inside kern_root"),
+            (r#""../kernel.img""#, "must be a path inside kern_root"),
+            (r#"{ path = "k", sha256 = "abc" }"#, "is not 64 hex digits"),
+            (
+                r#"{ path = "k", size = 1, min_size = 2 }"#,
+                "is below its min_size",
+            ),
+        ] {
+            let files = manifest(&format!("files = [{}]", entry)).files;
+            let checked = files[0].check().unwrap_err();
+            assert!(checked.contains(error), "{}: {}", entry, checked);
+        }
+    }
+
+    #[test]
+    fn adds_the_manifest_to_the_configured_files() {
+        let tmp = TempDir::new("manifest-load");
+        let configured = manifest(r#"files = ["kernel.img"]"#).files;
+        assert_eq!(required_files(&configured, tmp.path()).unwrap().len(), 1);
+
+        fs::write(tmp.join(MANIFEST_FILE), r#"files = ["initrd.img"]"#).unwrap();
+        let paths: Vec<String> = required_files(&configured, tmp.path())
+            .unwrap()
+            .iter()
+            .map(|file| file.path().to_string())
+            .collect();
+        assert_eq!(paths, ["kernel.img", "initrd.img"]);
+
+        fs::write(tmp.join(MANIFEST_FILE), r#"files = ["../escape"]"#).unwrap();
+        let error = load(tmp.path()).unwrap_err();
+        assert!(
+            error.contains("must be a path inside kern_root"),
+            "{}",
+            error
+        );
+    }
+}
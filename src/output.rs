@@ -0,0 +1,210 @@
+use std::fmt;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::process::Child;
+use tokio::sync::mpsc;
+
+use crate::logging::{self, SharedLog};
+
+/// Which pipe a line of child output came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+impl fmt::Display for Stream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stream::Stdout => write!(f, "stdout"),
+            Stream::Stderr => write!(f, "stderr"),
+        }
+    }
+}
+
+/// One line of child output, without the trailing newline
+#[derive(Debug, Clone)]
+pub struct Line {
+    pub stream: Stream,
+    pub text: String,
+    /// The line has no newline yet, e.g. a `login: ` prompt. It is sent
+    /// again, longer, as more of it arrives.
+    pub partial: bool,
+}
+
+/// Take the child's piped stdout and stderr and echo them to our own as
+/// they arrive. Complete lines are appended to `log`, if given, with their
+/// timestamps; every line, finished or not, goes to the returned receiver.
+/// The receiver closes once both pipes reach end of file.
+pub fn capture(child: &mut Child, log: Option<SharedLog>) -> mpsc::UnboundedReceiver<Line> {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    if let Some(stdout) = child.stdout.take() {
+        tokio::spawn(pump(
+            stdout,
+            tokio::io::stdout(),
+            Stream::Stdout,
+            sender.clone(),
+            log.clone(),
+        ));
+    }
+    if let Some(stderr) = child.stderr.take() {
+        tokio::spawn(pump(
+            stderr,
+            tokio::io::stderr(),
+            Stream::Stderr,
+            sender,
+            log,
+        ));
+    }
+    receiver
+}
+
+// Longest unfinished line that is still sent on as it grows; longer ones
+// are only sent once their newline arrives
+const MAX_PARTIAL: usize = 64 * 1024;
+
+// Copy one pipe, echoing each chunk as soon as it arrives so prompts show up
+// without a newline. Lines are logged once complete; unfinished ones are
+// also sent on so they can be matched.
+async fn pump(
+    mut pipe: impl AsyncRead + Unpin,
+    mut echo: impl AsyncWrite + Unpin,
+    stream: Stream,
+    sender: mpsc::UnboundedSender<Line>,
+    log: Option<SharedLog>,
+) {
+    let mut chunk = vec![0; 8192];
+    let mut pending = Vec::new();
+    loop {
+        let read = match pipe.read(&mut chunk).await {
+            Ok(0) | Err(_) => break,
+            Ok(read) => read,
+        };
+        let _ = echo.write_all(&chunk[..read]).await;
+        let _ = echo.flush().await;
+
+        pending.extend_from_slice(&chunk[..read]);
+        while let Some(end) = pending.iter().position(|&byte| byte == b'\n') {
+            let line: Vec<u8> = pending.drain(..=end).collect();
+            finish_line(&line, stream, &sender, log.as_ref());
+        }
+        if !pending.is_empty() && pending.len() <= MAX_PARTIAL {
+            // The supervisor may have stopped listening; keep echoing anyway
+            let _ = sender.send(Line {
+                stream,
+                text: String::from_utf8_lossy(&pending).to_string(),
+                partial: true,
+            });
+        }
+    }
+    if !pending.is_empty() {
+        finish_line(&pending, stream, &sender, log.as_ref());
+    }
+}
+
+// Log and send a complete line, keeping invalid UTF-8 readable
+fn finish_line(
+    line: &[u8],
+    stream: Stream,
+    sender: &mpsc::UnboundedSender<Line>,
+    log: Option<&SharedLog>,
+) {
+    let text = String::from_utf8_lossy(line)
+        .trim_end_matches(['\n', '\r'])
+        .to_string();
+    logging::write_shared(log, &stream.to_string(), &text);
+    let _ = sender.send(Line {
+        stream,
+        text,
+        partial: false,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn run_pump(
+        log: Option<SharedLog>,
+    ) -> (
+        tokio::io::DuplexStream,
+        tokio::io::DuplexStream,
+        mpsc::UnboundedReceiver<Line>,
+    ) {
+        let (guest, pipe) = tokio::io::duplex(1024);
+        let (echo, terminal) = tokio::io::duplex(1024);
+        let (sender, lines) = mpsc::unbounded_channel();
+        tokio::spawn(pump(pipe, echo, Stream::Stdout, sender, log));
+        (guest, terminal, lines)
+    }
+
+    async fn next(lines: &mut mpsc::UnboundedReceiver<Line>) -> Option<Line> {
+        tokio::time::timeout(Duration::from_secs(5), lines.recv())
+            .await
+            .expect("no output within 5s")
+    }
+
+    #[tokio::test]
+    async fn prompts_are_echoed_and_sent_before_their_newline() {
+        let (mut guest, mut terminal, mut lines) = run_pump(None);
+        guest.write_all(b"cozy login: ").await.unwrap();
+
+        let line = next(&mut lines).await.unwrap();
+        assert_eq!((line.text.as_str(), line.partial), ("cozy login: ", true));
+        let mut echoed = [0; 12];
+        tokio::time::timeout(Duration::from_secs(5), terminal.read_exact(&mut echoed))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&echoed, b"cozy login: ");
+    }
+
+    #[tokio::test]
+    async fn completes_lines_and_flushes_the_last_one() {
+        let (mut guest, _terminal, mut lines) = run_pump(None);
+        guest.write_all(b"cozy login: ").await.unwrap();
+        guest.write_all(b"\nwelcome\r\nbye").await.unwrap();
+        drop(guest);
+
+        let mut complete = Vec::new();
+        while let Some(line) = next(&mut lines).await {
+            if !line.partial {
+                complete.push(line.text);
+            }
+        }
+        assert_eq!(complete, ["cozy login: ", "welcome", "bye"]);
+    }
+
+    #[tokio::test]
+    async fn logging_keeps_whole_lines_without_delaying_the_echo() {
+        let dir = crate::test_support::TempDir::new("tee");
+        let config = logging::LoggingConfig {
+            dir: None,
+            max_size: logging::ByteSize(1 << 20),
+            max_files: 10,
+        };
+        let log = logging::BootLog::create(dir.path(), None, &config).unwrap();
+        let path = log.path();
+        let (mut guest, mut terminal, mut lines) =
+            run_pump(Some(std::sync::Arc::new(std::sync::Mutex::new(log))));
+
+        guest.write_all(b"cozy login: ").await.unwrap();
+        let mut echoed = [0; 12];
+        tokio::time::timeout(Duration::from_secs(5), terminal.read_exact(&mut echoed))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(next(&mut lines).await.unwrap().partial);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "");
+
+        guest.write_all(b"root\n").await.unwrap();
+        drop(guest);
+        while next(&mut lines).await.is_some() {}
+        let logged = std::fs::read_to_string(&path).unwrap();
+        let logged: Vec<&str> = logged
+            .lines()
+            .map(|line| line.split_once(' ').unwrap().1)
+            .collect();
+        assert_eq!(logged, ["[stdout] cozy login: root"]);
+    }
+}
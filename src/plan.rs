@@ -0,0 +1,130 @@
+use serde::Serialize;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// A fully resolved process to launch: program, arguments, environment and
+/// working directory. Backends produce one of these from a resolved boot.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BootPlan {
+    pub program: PathBuf,
+    pub args: Vec<String>,
+    /// Extra environment variables, on top of the inherited environment
+    pub env: Vec<(String, String)>,
+    /// Working directory; None inherits the current one
+    pub cwd: Option<PathBuf>,
+}
+
+impl BootPlan {
+    /// Start building a plan that runs `program`
+    pub fn builder(program: impl Into<PathBuf>) -> BootPlanBuilder {
+        BootPlanBuilder {
+            plan: BootPlan {
+                program: program.into(),
+                args: Vec::new(),
+                env: Vec::new(),
+                cwd: None,
+            },
+        }
+    }
+
+    /// Build a `Command` that runs this plan
+    pub fn to_command(&self) -> Command {
+        let mut command = Command::new(&self.program);
+        command.args(&self.args);
+        command.envs(self.env.iter().map(|(key, value)| (key, value)));
+        if let Some(cwd) = &self.cwd {
+            command.current_dir(cwd);
+        }
+        command
+    }
+}
+
+/// Builder for [`BootPlan`]
+#[derive(Debug, Clone)]
+pub struct BootPlanBuilder {
+    plan: BootPlan,
+}
+
+impl BootPlanBuilder {
+    pub fn arg(mut self, arg: impl Into<String>) -> Self {
+        self.plan.args.push(arg.into());
+        self
+    }
+
+    pub fn args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.plan.args.extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    /// Set an environment variable, replacing an earlier value for the same key
+    pub fn env(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        let key = key.into();
+        self.plan.env.retain(|(existing, _)| *existing != key);
+        self.plan.env.push((key, value.into()));
+        self
+    }
+
+    pub fn current_dir(mut self, cwd: impl Into<PathBuf>) -> Self {
+        self.plan.cwd = Some(cwd.into());
+        self
+    }
+
+    pub fn build(self) -> BootPlan {
+        self.plan
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsStr;
+    use std::path::Path;
+
+    #[test]
+    fn later_env_values_replace_earlier_ones() {
+        let plan = BootPlan::builder("cozy-os")
+            .env("A", "1")
+            .env("B", "2")
+            .env("A", "3")
+            .build();
+        assert_eq!(
+            plan.env,
+            [
+                ("B".to_string(), "2".to_string()),
+                ("A".to_string(), "3".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn commands_match_the_plan() {
+        let plan = BootPlan::builder("/bin/cozy-os")
+            .arg("--debug")
+            .args(["--kern-root", "/kern"])
+            .env("COZY_LOG", "trace")
+            .current_dir("/work")
+            .build();
+        let command = plan.to_command();
+        assert_eq!(command.get_program(), "/bin/cozy-os");
+        assert_eq!(
+            command.get_args().collect::<Vec<_>>(),
+            ["--debug", "--kern-root", "/kern"]
+        );
+        assert_eq!(
+            command.get_envs().collect::<Vec<_>>(),
+            [(OsStr::new("COZY_LOG"), Some(OsStr::new("trace")))]
+        );
+        assert_eq!(command.get_current_dir(), Some(Path::new("/work")));
+    }
+
+    #[test]
+    fn inherits_the_cwd_unless_set() {
+        let plan = BootPlan::builder("cozy-os").build();
+        assert_eq!(plan.cwd, None);
+        assert_eq!(plan.to_command().get_current_dir(), None);
+    }
+}
@@ -0,0 +1,217 @@
+use toml::{Table, Value};
+
+/// A profile chosen for this boot, with the tables to merge over the config
+#[derive(Debug)]
+pub struct SelectedProfile {
+    pub name: String,
+    /// Profile tables from the most distant ancestor to the selected profile,
+    /// with `inherits` already stripped
+    pub chain: Vec<(String, Table)>,
+}
+
+/// Select a profile from the `[profiles.<name>]` tables and resolve its
+/// `inherits` chain. `profiles` and `default_profile` are removed from `root`.
+///
+/// The profile named on the command line wins over `default_profile`. When
+/// neither is set no profile is selected.
+pub fn select_profile(
+    root: &mut Table,
+    requested: Option<&str>,
+) -> Result<Option<SelectedProfile>, String> {
+    let profiles = match root.remove("profiles") {
+        Some(Value::Table(profiles)) => profiles,
+        Some(_) => {
+            return Err("'profiles' must be a table of [profiles.<name>] sections".to_string())
+        }
+        None => Table::new(),
+    };
+
+    let default_profile = match root.remove("default_profile") {
+        Some(Value::String(name)) => Some(name),
+        Some(_) => return Err("'default_profile' must be a string".to_string()),
+        None => None,
+    };
+
+    let selected = match requested.map(str::to_string).or(default_profile) {
+        Some(name) => name,
+        None => return Ok(None),
+    };
+
+    // Walk the inherits chain from the selected profile up to its root
+    let mut chain: Vec<String> = Vec::new();
+    let mut current = Some(selected.clone());
+    while let Some(name) = current {
+        if chain.contains(&name) {
+            chain.push(name);
+            return Err(format!("profile inheritance cycle: {}", chain.join(" -> ")));
+        }
+
+        let profile = match profiles.get(&name) {
+            Some(Value::Table(profile)) => profile,
+            Some(_) => return Err(format!("profile '{}' must be a table", name)),
+            None if chain.is_empty() => return Err(unknown_profile(&name, &profiles)),
+            None => {
+                return Err(format!(
+                    "profile '{}' inherits from unknown profile '{}'",
+                    chain.last().unwrap(),
+                    name
+                ));
+            }
+        };
+
+        current = match profile.get("inherits") {
+            Some(Value::String(parent)) => Some(parent.clone()),
+            Some(_) => return Err(format!("profile '{}': 'inherits' must be a string", name)),
+            None => None,
+        };
+        chain.push(name);
+    }
+
+    // The most distant ancestor comes first so nearer profiles override it
+    let chain = chain
+        .iter()
+        .rev()
+        .filter_map(|name| match profiles.get(name) {
+            Some(Value::Table(profile)) => {
+                let mut profile = profile.clone();
+                profile.remove("inherits");
+                Some((name.clone(), profile))
+            }
+            _ => None,
+        })
+        .collect();
+
+    Ok(Some(SelectedProfile {
+        name: selected,
+        chain,
+    }))
+}
+
+fn unknown_profile(name: &str, profiles: &Table) -> String {
+    if profiles.is_empty() {
+        format!(
+            "profile '{}' not found: the config defines no profiles",
+            name
+        )
+    } else {
+        let available: Vec<&str> = profiles.keys().map(String::as_str).collect();
+        format!(
+            "profile '{}' not found (available: {})",
+            name,
+            available.join(", ")
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROFILES: &str = r#"
+default_profile = "base"
+
+[main]
+kern_root = "/k"
+
+[profiles.base.bootargs]
+loglevel = "info"
+
+[profiles.debug]
+inherits = "base"
+[profiles.debug.bootargs]
+loglevel = "debug"
+
+[profiles.debug-minimal]
+inherits = "debug"
+[profiles.debug-minimal.bin]
+allow_universal = false
+"#;
+
+    fn select(text: &str, requested: Option<&str>) -> Result<Option<SelectedProfile>, String> {
+        let mut root: Table = toml::from_str(text).unwrap();
+        select_profile(&mut root, requested)
+    }
+
+    fn names(profile: &SelectedProfile) -> Vec<&str> {
+        profile
+            .chain
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn resolves_inherits_from_the_most_distant_ancestor() {
+        let profile = select(PROFILES, Some("debug-minimal")).unwrap().unwrap();
+        assert_eq!(profile.name, "debug-minimal");
+        assert_eq!(names(&profile), ["base", "debug", "debug-minimal"]);
+        assert!(profile
+            .chain
+            .iter()
+            .all(|(_, table)| !table.contains_key("inherits")));
+        assert_eq!(
+            profile.chain[1].1["bootargs"]["loglevel"].as_str(),
+            Some("debug")
+        );
+    }
+
+    #[test]
+    fn requested_profile_wins_over_default() {
+        assert_eq!(names(&select(PROFILES, None).unwrap().unwrap()), ["base"]);
+        assert_eq!(
+            names(&select(PROFILES, Some("debug")).unwrap().unwrap()),
+            ["base", "debug"]
+        );
+        assert!(select("[main]\n", None).unwrap().is_none());
+    }
+
+    #[test]
+    fn removes_profiles_from_the_config() {
+        let mut root: Table = toml::from_str(PROFILES).unwrap();
+        select_profile(&mut root, None).unwrap();
+        assert_eq!(root.keys().collect::<Vec<_>>(), ["main"]);
+    }
+
+    #[test]
+    fn rejects_inheritance_cycles() {
+        let text = "[profiles.a]\ninherits = \"b\"\n[profiles.b]\ninherits = \"c\"\n[profiles.c]\ninherits = \"a\"\n";
+        assert_eq!(
+            select(text, Some("a")).unwrap_err(),
+            "profile inheritance cycle: a -> b -> c -> a"
+        );
+        let text = "[profiles.self]\ninherits = \"self\"\n";
+        assert_eq!(
+            select(text, Some("self")).unwrap_err(),
+            "profile inheritance cycle: self -> self"
+        );
+    }
+
+    #[test]
+    fn reports_unknown_profiles() {
+        assert_eq!(
+            select(PROFILES, Some("release")).unwrap_err(),
+            "profile 'release' not found (available: base, debug, debug-minimal)"
+        );
+        assert_eq!(
+            select("[main]\n", Some("release")).unwrap_err(),
+            "profile 'release' not found: the config defines no profiles"
+        );
+        let text = "[profiles.a]\ninherits = \"missing\"\n";
+        assert_eq!(
+            select(text, Some("a")).unwrap_err(),
+            "profile 'a' inherits from unknown profile 'missing'"
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_profiles() {
+        assert!(select("profiles = 1\n", None).is_err());
+        assert!(select("default_profile = 1\n", None).is_err());
+        assert!(select("[profiles]\na = 1\n", Some("a"))
+            .unwrap_err()
+            .contains("must be a table"));
+        assert!(select("[profiles.a]\ninherits = 1\n", Some("a"))
+            .unwrap_err()
+            .contains("'inherits' must be a string"));
+    }
+}
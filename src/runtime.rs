@@ -0,0 +1,325 @@
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::env;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::backend::BackendKind;
+use crate::duration::HumanDuration;
+use crate::vars::{expand_variables, Variables};
+
+/// Name of the emulator binary looked up when no executable is configured
+pub const DEFAULT_EXECUTABLE: &str = "cozy-os";
+
+/// The `[runtime]` section
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct RuntimeConfig {
+    /// Path to the cozy-os executable; `$(...)` references are expanded
+    pub executable: Option<String>,
+    /// Oldest cozy-os version allowed to boot, e.g. "0.4"
+    pub min_version: Option<String>,
+    /// Newest cozy-os version allowed to boot, e.g. "0.9" or "0.9.2". Only
+    /// the components given are compared, so "0.9" allows 0.9.5 but not 0.10
+    pub max_version: Option<String>,
+    /// How the boot is launched: "cozy-os" (default) or "template"
+    #[serde(default)]
+    pub backend: BackendKind,
+    /// argv pattern for the template backend
+    pub template: Option<Vec<String>>,
+    /// Wall-clock limit for the whole run, e.g. "90s" or "5m"
+    pub timeout: Option<HumanDuration>,
+    /// Text CozyOS prints once it has booted, e.g. "login:"
+    pub ready_text: Option<String>,
+    /// How long after starting CozyOS may take to print `ready_text`
+    pub ready_timeout: Option<HumanDuration>,
+}
+
+/// A dotted numeric version such as `0.4.1`; missing components count as 0
+#[derive(Debug, Clone)]
+pub struct Version(Vec<u64>);
+
+impl Version {
+    /// Parse `1.2.3`, also accepting a leading `v` and ignoring any
+    /// pre-release or build suffix (`1.2.3-dev+abc`)
+    pub fn parse(input: &str) -> Option<Self> {
+        let core = input.trim().trim_start_matches('v');
+        let core = core.split(['-', '+']).next()?;
+        let parts = core
+            .split('.')
+            .map(|part| part.parse::<u64>().ok())
+            .collect::<Option<Vec<u64>>>()?;
+        if parts.is_empty() {
+            None
+        } else {
+            Some(Version(parts))
+        }
+    }
+
+    /// Whether this version is no newer than `max`, comparing only as many
+    /// components as `max` has: `0.9.2` is at most `0.9`, `0.10` is not
+    pub fn at_most(&self, max: &Version) -> bool {
+        let compared = Version(self.0.iter().take(max.0.len()).copied().collect());
+        compared <= *max
+    }
+
+    // Find the first word of `--version` output that looks like a version
+    fn find_in(output: &str) -> Option<Self> {
+        output
+            .split_whitespace()
+            .filter(|word| {
+                word.trim_start_matches('v')
+                    .starts_with(|c: char| c.is_ascii_digit())
+            })
+            .find_map(Version::parse)
+    }
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let len = self.0.len().max(other.0.len());
+        (0..len)
+            .map(|idx| {
+                self.0
+                    .get(idx)
+                    .unwrap_or(&0)
+                    .cmp(other.0.get(idx).unwrap_or(&0))
+            })
+            .find(|ordering| *ordering != Ordering::Equal)
+            .unwrap_or(Ordering::Equal)
+    }
+}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Version {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Version {}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let parts: Vec<String> = self.0.iter().map(u64::to_string).collect();
+        write!(f, "{}", parts.join("."))
+    }
+}
+
+/// Pick the cozy-os executable.
+///
+/// An explicit path (`--cozy-os`, then `[runtime] executable`) must exist.
+/// Otherwise `$(devroot)/target/release` and `$(devroot)/target/debug` are
+/// searched before `PATH`; if nothing is found the bare name is returned so
+/// the error surfaces when it is started.
+pub fn resolve_executable(explicit: Option<&str>, vars: &Variables) -> Result<PathBuf, String> {
+    if let Some(executable) = explicit {
+        let expanded = expand_variables(executable, vars).map_err(|e| {
+            format!(
+                "failed to expand cozy-os executable '{}': {}",
+                executable, e
+            )
+        })?;
+        let path = PathBuf::from(&expanded);
+
+        // A bare name is looked up on PATH like the default
+        if path.components().count() == 1 && !path.exists() {
+            return find_on_path(&expanded)
+                .ok_or_else(|| format!("cozy-os executable '{}' was not found on PATH", expanded));
+        }
+        if !path.is_file() {
+            return Err(format!(
+                "cozy-os executable '{}' does not exist (expanded from '{}')",
+                expanded, executable
+            ));
+        }
+        return Ok(path);
+    }
+
+    if let Ok(devroot) = vars.expand("$(devroot)") {
+        for profile in ["release", "debug"] {
+            let candidate = Path::new(&devroot)
+                .join("target")
+                .join(profile)
+                .join(DEFAULT_EXECUTABLE);
+            if candidate.is_file() {
+                return Ok(candidate.canonicalize().unwrap_or(candidate));
+            }
+        }
+    }
+
+    Ok(find_on_path(DEFAULT_EXECUTABLE).unwrap_or_else(|| PathBuf::from(DEFAULT_EXECUTABLE)))
+}
+
+fn find_on_path(name: &str) -> Option<PathBuf> {
+    let path = env::var_os("PATH")?;
+    env::split_paths(&path)
+        .map(|dir| dir.join(name))
+        .find(|candidate| candidate.is_file())
+}
+
+/// Run `<executable> --version` and parse the reported version
+pub fn probe_version(executable: &Path) -> Result<Version, String> {
+    let output = Command::new(executable)
+        .arg("--version")
+        .output()
+        .map_err(|e| format!("failed to run '{} --version': {}", executable.display(), e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "'{} --version' exited with {}",
+            executable.display(),
+            output.status
+        ));
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    Version::find_in(&stdout).ok_or_else(|| {
+        format!(
+            "could not find a version in the output of '{} --version': {}",
+            executable.display(),
+            stdout.trim()
+        )
+    })
+}
+
+/// Refuse versions outside `[runtime] min_version` / `max_version`.
+/// Returns the probed version, or None when no range is configured.
+pub fn check_version(
+    executable: &Path,
+    runtime: &RuntimeConfig,
+) -> Result<Option<Version>, String> {
+    if runtime.min_version.is_none() && runtime.max_version.is_none() {
+        return Ok(None);
+    }
+
+    let version = probe_version(executable)?;
+    let bound = |key: &str, value: &Option<String>| -> Result<Option<Version>, String> {
+        value
+            .as_deref()
+            .map(|value| {
+                Version::parse(value)
+                    .ok_or_else(|| format!("runtime.{} '{}' is not a version", key, value))
+            })
+            .transpose()
+    };
+
+    if let Some(min) = bound("min_version", &runtime.min_version)? {
+        if version < min {
+            return Err(format!(
+                "{} is version {}, but at least {} is required (runtime.min_version)",
+                executable.display(),
+                version,
+                min
+            ));
+        }
+    }
+    if let Some(max) = bound("max_version", &runtime.max_version)? {
+        if !version.at_most(&max) {
+            return Err(format!(
+                "{} is version {}, but at most {} is supported (runtime.max_version)",
+                executable.display(),
+                version,
+                max
+            ));
+        }
+    }
+
+    Ok(Some(version))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn version(input: &str) -> Version {
+        Version::parse(input).unwrap()
+    }
+
+    // A fake cozy-os whose `--version` prints `output`
+    fn fake_cozy_os(tmp: &TempDir, output: &str) -> PathBuf {
+        let path = tmp.join("cozy-os");
+        fs::write(&path, format!("#!/bin/sh\necho '{}'\n", output)).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        // A test forking at the same time may briefly hold the file open for writing
+        while let Err(e) = Command::new(&path).output() {
+            assert_eq!(e.raw_os_error(), Some(libc::ETXTBSY), "{}", e);
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        path
+    }
+
+    fn range(min: Option<&str>, max: Option<&str>) -> RuntimeConfig {
+        RuntimeConfig {
+            min_version: min.map(str::to_string),
+            max_version: max.map(str::to_string),
+            ..RuntimeConfig::default()
+        }
+    }
+
+    #[test]
+    fn parses_versions() {
+        assert_eq!(version("v1.2.3-dev+abc").to_string(), "1.2.3");
+        assert_eq!(version("0.9"), version("0.9.0"));
+        assert!(version("0.10") > version("0.9.7"));
+        for input in ["", "v", "1..2", "one"] {
+            assert!(Version::parse(input).is_none(), "{:?}", input);
+        }
+        assert_eq!(
+            Version::find_in("cozy-os v0.9.2 (built 2026-01-01)").map(|v| v.to_string()),
+            Some("0.9.2".to_string())
+        );
+    }
+
+    #[test]
+    fn max_version_compares_only_the_parts_given() {
+        assert!(version("0.9.2").at_most(&version("0.9")));
+        assert!(version("0.9").at_most(&version("0.9.2")));
+        assert!(version("0.8.99").at_most(&version("0.9")));
+        assert!(!version("0.10").at_most(&version("0.9")));
+        assert!(!version("0.9.3").at_most(&version("0.9.2")));
+        assert!(!version("1").at_most(&version("0")));
+    }
+
+    #[test]
+    fn gates_the_probed_version() {
+        let tmp = TempDir::new("version");
+        let cozy_os = fake_cozy_os(&tmp, "cozy-os 0.9.2");
+
+        assert_eq!(check_version(&cozy_os, &range(None, None)).unwrap(), None);
+        for (min, max) in [(Some("0.4"), Some("0.9")), (Some("0.9.2"), Some("0.9.2"))] {
+            let checked = check_version(&cozy_os, &range(min, max)).unwrap();
+            assert_eq!(checked, Some(version("0.9.2")));
+        }
+        let error = check_version(&cozy_os, &range(None, Some("0.8"))).unwrap_err();
+        assert!(
+            error.ends_with("is version 0.9.2, but at most 0.8 is supported (runtime.max_version)"),
+            "{}",
+            error
+        );
+        let error = check_version(&cozy_os, &range(Some("0.10"), None)).unwrap_err();
+        assert!(
+            error
+                .ends_with("is version 0.9.2, but at least 0.10 is required (runtime.min_version)"),
+            "{}",
+            error
+        );
+        let error = check_version(&cozy_os, &range(None, Some("latest"))).unwrap_err();
+        assert_eq!(error, "runtime.max_version 'latest' is not a version");
+    }
+
+    #[test]
+    fn reports_unversioned_executables() {
+        let tmp = TempDir::new("no-version");
+        let cozy_os = fake_cozy_os(&tmp, "cozy-os development build");
+        let error = probe_version(&cozy_os).unwrap_err();
+        assert!(error.starts_with("could not find a version"), "{}", error);
+    }
+}
@@ -0,0 +1,531 @@
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::logging::utc_parts;
+use crate::user_root::{copy_tree, remove_tree};
+use crate::vars::{expand_variables, Variables};
+
+/// Prefix of snapshots taken by `[main] snapshot_on_boot`; only these are pruned
+pub const AUTO_PREFIX: &str = "auto-";
+
+/// Where snapshots of `user_root` go when `[main] snapshot_dir` is unset.
+/// A sibling of the user root, so hardlinks between snapshots stay on one filesystem.
+pub fn default_dir(user_root: &Path) -> PathBuf {
+    let name = user_root
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    user_root.with_file_name(format!("{}.snapshots", name))
+}
+
+/// The expanded `[main] snapshot_dir`, or the default next to `user_root`
+pub fn resolve_dir(
+    configured: Option<&str>,
+    vars: &Variables,
+    user_root: &Path,
+) -> Result<PathBuf, String> {
+    match configured {
+        Some(dir) => expand_variables(dir, vars)
+            .map(PathBuf::from)
+            .map_err(|e| format!("failed to expand main.snapshot_dir '{}': {}", dir, e)),
+        None => Ok(default_dir(user_root)),
+    }
+}
+
+/// One snapshot in the snapshot directory
+#[derive(Debug, Clone)]
+pub struct SnapshotEntry {
+    pub id: String,
+    pub path: PathBuf,
+    pub created: SystemTime,
+}
+
+impl SnapshotEntry {
+    pub fn is_auto(&self) -> bool {
+        self.id.starts_with(AUTO_PREFIX)
+    }
+}
+
+/// What creating a snapshot did
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Stats {
+    /// Regular files in the snapshot
+    pub files: u64,
+    /// Files hardlinked to the previous snapshot instead of copied
+    pub linked: u64,
+    /// Bytes actually copied
+    pub copied_bytes: u64,
+}
+
+/// Every snapshot in `dir`, oldest first by the time it was created
+pub fn list(dir: &Path) -> Result<Vec<SnapshotEntry>, String> {
+    let read = match fs::read_dir(dir) {
+        Ok(read) => read,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => {
+            return Err(format!(
+                "failed to read snapshot directory {}: {}",
+                dir.display(),
+                e
+            ))
+        }
+    };
+
+    let mut entries = Vec::new();
+    for entry in read.flatten() {
+        let id = entry.file_name().to_string_lossy().to_string();
+        // Unfinished snapshots are hidden until they are renamed into place
+        if id.starts_with('.') || !entry.file_type().is_ok_and(|kind| kind.is_dir()) {
+            continue;
+        }
+        let created = read_created(dir, &id).unwrap_or_else(|| {
+            entry
+                .metadata()
+                .and_then(|metadata| metadata.modified())
+                .unwrap_or(SystemTime::UNIX_EPOCH)
+        });
+        entries.push(SnapshotEntry {
+            id,
+            path: entry.path(),
+            created,
+        });
+    }
+    entries.sort_by(|a, b| a.created.cmp(&b.created).then_with(|| a.id.cmp(&b.id)));
+    Ok(entries)
+}
+
+// The creation time of a snapshot is kept next to it, since the directory's
+// own mtime changes whenever it is touched or copied
+fn created_path(dir: &Path, id: &str) -> PathBuf {
+    dir.join(format!(".{}.created", id))
+}
+
+fn read_created(dir: &Path, id: &str) -> Option<SystemTime> {
+    let nanos: u64 = fs::read_to_string(created_path(dir, id))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()?;
+    SystemTime::UNIX_EPOCH.checked_add(Duration::from_nanos(nanos))
+}
+
+fn write_created(dir: &Path, id: &str, created: SystemTime) -> io::Result<()> {
+    let nanos = created
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    fs::write(created_path(dir, id), nanos.to_string())
+}
+
+fn find(dir: &Path, id: &str) -> Result<SnapshotEntry, String> {
+    list(dir)?
+        .into_iter()
+        .find(|entry| entry.id == id)
+        .ok_or_else(|| format!("no snapshot '{}' in {}", id, dir.display()))
+}
+
+/// Check a snapshot name given on the command line
+pub fn check_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+        return Err(format!(
+            "invalid snapshot name '{}': use letters, digits, '-' or '_'",
+            name
+        ));
+    }
+    if name.starts_with(AUTO_PREFIX) {
+        return Err(format!(
+            "invalid snapshot name '{}': the '{}' prefix is kept for snapshot_on_boot",
+            name, AUTO_PREFIX
+        ));
+    }
+    Ok(())
+}
+
+/// Copy `user_root` into a new snapshot named `name`, or after the current
+/// time. Files unchanged since the newest snapshot are hardlinked to it
+/// rather than copied.
+pub fn create(
+    dir: &Path,
+    user_root: &Path,
+    name: Option<&str>,
+    auto: bool,
+) -> Result<(SnapshotEntry, Stats), String> {
+    if !user_root.is_dir() {
+        return Err(format!(
+            "user root '{}' is not a directory",
+            user_root.display()
+        ));
+    }
+    fs::create_dir_all(dir).map_err(|e| {
+        format!(
+            "failed to create snapshot directory {}: {}",
+            dir.display(),
+            e
+        )
+    })?;
+
+    let existing = list(dir)?;
+    let id = match name {
+        Some(name) => {
+            check_name(name)?;
+            if existing.iter().any(|entry| entry.id == name) {
+                return Err(format!("snapshot '{}' already exists", name));
+            }
+            name.to_string()
+        }
+        None => {
+            let (date, time) = utc_parts(SystemTime::now());
+            let base = format!(
+                "{}{}-{}",
+                if auto { AUTO_PREFIX } else { "" },
+                date.replace('-', ""),
+                time[..8].replace(':', "")
+            );
+            (1..)
+                .map(|n| {
+                    if n == 1 {
+                        base.clone()
+                    } else {
+                        format!("{}-{}", base, n)
+                    }
+                })
+                .find(|id| !existing.iter().any(|entry| entry.id == *id))
+                .expect("some suffix is free")
+        }
+    };
+
+    let staging = dir.join(format!(".{}.partial", id));
+    let previous = existing.last().map(|entry| entry.path.clone());
+    let mut stats = Stats::default();
+    snapshot_tree(user_root, &staging, previous.as_deref(), &mut stats)
+        .and_then(|()| write_created(dir, &id, SystemTime::now()))
+        .and_then(|()| fs::rename(&staging, dir.join(&id)))
+        .map_err(|e| {
+            let _ = remove_tree(&staging);
+            let _ = fs::remove_file(created_path(dir, &id));
+            format!("failed to snapshot {}: {}", user_root.display(), e)
+        })?;
+
+    Ok((find(dir, &id)?, stats))
+}
+
+// Copy `from` to `to`, hardlinking files that are identical in `previous`
+fn snapshot_tree(
+    from: &Path,
+    to: &Path,
+    previous: Option<&Path>,
+    stats: &mut Stats,
+) -> io::Result<()> {
+    fs::create_dir(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let (source, target) = (entry.path(), to.join(entry.file_name()));
+        let earlier = previous.map(|previous| previous.join(entry.file_name()));
+        let kind = entry.file_type()?;
+        if kind.is_dir() {
+            snapshot_tree(&source, &target, earlier.as_deref(), stats)?;
+        } else if kind.is_symlink() {
+            std::os::unix::fs::symlink(fs::read_link(&source)?, &target)?;
+        } else if kind.is_file() {
+            let metadata = entry.metadata()?;
+            stats.files += 1;
+            if let Some(earlier) = earlier.filter(|earlier| unchanged(&metadata, earlier)) {
+                fs::hard_link(earlier, &target)?;
+                stats.linked += 1;
+            } else {
+                fs::copy(&source, &target)?;
+                // Keep the mtime so the next snapshot can tell the file is unchanged
+                fs::File::open(&target)?.set_modified(metadata.modified()?)?;
+                stats.copied_bytes += metadata.len();
+            }
+        }
+        // Sockets, FIFOs and devices are skipped: they cannot be restored meaningfully
+    }
+    // Last, so a read-only directory can still be filled
+    fs::set_permissions(to, fs::metadata(from)?.permissions())
+}
+
+// Same size, mtime and mode as the copy in the previous snapshot
+fn unchanged(current: &fs::Metadata, earlier: &Path) -> bool {
+    let Ok(earlier) = fs::symlink_metadata(earlier) else {
+        return false;
+    };
+    earlier.is_file()
+        && earlier.len() == current.len()
+        && earlier.mode() == current.mode()
+        && earlier.modified().ok() == current.modified().ok()
+}
+
+/// Replace `user_root` with a copy of snapshot `id`. The snapshot itself is
+/// left untouched, so it can be restored again.
+pub fn restore(dir: &Path, id: &str, user_root: &Path) -> Result<u64, String> {
+    let snapshot = find(dir, id)?;
+    let name = user_root
+        .file_name()
+        .ok_or_else(|| format!("cannot restore into '{}'", user_root.display()))?
+        .to_string_lossy()
+        .to_string();
+    let staging = user_root.with_file_name(format!(".{}.restoring-{}", name, std::process::id()));
+    let replaced = user_root.with_file_name(format!(".{}.replaced-{}", name, std::process::id()));
+
+    let copied = copy_tree(&snapshot.path, &staging).map_err(|e| {
+        let _ = remove_tree(&staging);
+        format!("failed to copy snapshot '{}': {}", id, e)
+    })?;
+    swap_in(&staging, user_root, &replaced, |from, to| {
+        fs::rename(from, to)
+    })?;
+    if replaced.exists() {
+        remove_tree(&replaced).map_err(|e| {
+            format!(
+                "restored, but failed to remove the old tree {}: {}",
+                replaced.display(),
+                e
+            )
+        })?;
+    }
+    Ok(copied)
+}
+
+// Move `staging` to `target`, moving an existing `target` to `backup` first.
+// When that fails, the backup is put back, or the error says where it is.
+fn swap_in(
+    staging: &Path,
+    target: &Path,
+    backup: &Path,
+    mut rename: impl FnMut(&Path, &Path) -> io::Result<()>,
+) -> Result<(), String> {
+    let had_target = target.exists();
+    if had_target {
+        rename(target, backup).map_err(|e| {
+            let _ = remove_tree(staging);
+            format!("failed to move {} aside: {}", target.display(), e)
+        })?;
+    }
+    let Err(e) = rename(staging, target) else {
+        return Ok(());
+    };
+    let _ = remove_tree(staging);
+    let mut message = format!(
+        "failed to move the restored tree to {}: {}",
+        target.display(),
+        e
+    );
+    if had_target {
+        if let Err(undo) = rename(backup, target) {
+            message.push_str(&format!(
+                "; the previous tree is still at {} ({})",
+                backup.display(),
+                undo
+            ));
+        }
+    }
+    Err(message)
+}
+
+/// Delete snapshot `id`
+pub fn delete(dir: &Path, id: &str) -> Result<(), String> {
+    let snapshot = find(dir, id)?;
+    remove_tree(&snapshot.path)
+        .map_err(|e| format!("failed to delete snapshot '{}': {}", id, e))?;
+    let _ = fs::remove_file(created_path(dir, id));
+    Ok(())
+}
+
+/// Delete the oldest automatic snapshots beyond `keep`, returning their ids.
+/// Snapshots created by hand are never pruned.
+pub fn prune(dir: &Path, keep: usize) -> Result<Vec<String>, String> {
+    let auto: Vec<SnapshotEntry> = list(dir)?
+        .into_iter()
+        .filter(SnapshotEntry::is_auto)
+        .collect();
+    let excess = auto.len().saturating_sub(keep);
+    let mut deleted = Vec::new();
+    for entry in &auto[..excess] {
+        delete(dir, &entry.id)?;
+        deleted.push(entry.id.clone());
+    }
+    Ok(deleted)
+}
+
+/// Number of files in a snapshot and their total size
+pub fn usage(path: &Path) -> (u64, u64) {
+    let mut totals = (0, 0);
+    let Ok(read) = fs::read_dir(path) else {
+        return totals;
+    };
+    for entry in read.flatten() {
+        let Ok(kind) = entry.file_type() else {
+            continue;
+        };
+        if kind.is_dir() {
+            let (files, size) = usage(&entry.path());
+            totals = (totals.0 + files, totals.1 + size);
+        } else if kind.is_file() {
+            totals = (
+                totals.0 + 1,
+                totals.1 + entry.metadata().map(|metadata| metadata.len()).unwrap_or(0),
+            );
+        }
+    }
+    totals
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    fn read(path: &Path) -> String {
+        fs::read_to_string(path).unwrap()
+    }
+
+    fn ids(dir: &Path) -> Vec<String> {
+        list(dir)
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.id)
+            .collect()
+    }
+
+    #[test]
+    fn restores_what_was_snapshotted() {
+        let tmp = TempDir::new("snapshot-roundtrip").with_files(&["user/etc/motd", "user/home/a"]);
+        let (user_root, dir) = (tmp.join("user"), tmp.join("snapshots"));
+        std::os::unix::fs::symlink("etc/motd", user_root.join("motd")).unwrap();
+
+        let (entry, stats) = create(&dir, &user_root, Some("clean"), false).unwrap();
+        assert_eq!(
+            (entry.id.as_str(), stats.files, stats.linked),
+            ("clean", 2, 0)
+        );
+
+        fs::write(user_root.join("etc/motd"), "changed").unwrap();
+        fs::write(user_root.join("new"), "new").unwrap();
+        assert_eq!(restore(&dir, "clean", &user_root).unwrap(), 2);
+        assert_eq!(read(&user_root.join("etc/motd")), "user/etc/motd");
+        assert_eq!(read(&user_root.join("motd")), "user/etc/motd");
+        assert!(!user_root.join("new").exists());
+        // Nothing is left behind next to the user root
+        let mut names: Vec<String> = fs::read_dir(tmp.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["snapshots", "user"]);
+    }
+
+    #[test]
+    fn unchanged_files_are_hardlinked() {
+        let tmp = TempDir::new("snapshot-links").with_files(&["user/a", "user/b"]);
+        let (user_root, dir) = (tmp.join("user"), tmp.join("snapshots"));
+        create(&dir, &user_root, Some("one"), false).unwrap();
+        fs::write(user_root.join("b"), "changed").unwrap();
+        let (_, stats) = create(&dir, &user_root, Some("two"), false).unwrap();
+        assert_eq!((stats.files, stats.linked), (2, 1));
+        assert_eq!(read(&dir.join("one/b")), "user/b");
+    }
+
+    #[test]
+    fn order_survives_touching_a_snapshot() {
+        let tmp = TempDir::new("snapshot-order").with_files(&["user/a"]);
+        let (user_root, dir) = (tmp.join("user"), tmp.join("snapshots"));
+        for name in ["first", "second", "third"] {
+            create(&dir, &user_root, Some(name), false).unwrap();
+        }
+        let future = SystemTime::now() + Duration::from_secs(3600);
+        fs::File::open(dir.join("first"))
+            .unwrap()
+            .set_modified(future)
+            .unwrap();
+        assert_eq!(ids(&dir), ["first", "second", "third"]);
+    }
+
+    #[test]
+    fn prunes_only_the_oldest_auto_snapshots() {
+        let tmp = TempDir::new("snapshot-prune").with_files(&["user/a"]);
+        let (user_root, dir) = (tmp.join("user"), tmp.join("snapshots"));
+        let mut auto = Vec::new();
+        for n in 0..4 {
+            auto.push(create(&dir, &user_root, None, true).unwrap().0.id);
+            if n == 1 {
+                create(&dir, &user_root, Some("manual"), false).unwrap();
+            }
+        }
+        assert!(auto.iter().all(|id| id.starts_with(AUTO_PREFIX)));
+
+        assert_eq!(prune(&dir, 2).unwrap(), auto[..2]);
+        assert_eq!(ids(&dir), ["manual", &auto[2], &auto[3]]);
+        assert!(!created_path(&dir, &auto[0]).exists());
+        assert!(prune(&dir, 2).unwrap().is_empty());
+    }
+
+    #[test]
+    fn failed_restore_puts_the_user_root_back() {
+        let tmp = TempDir::new("snapshot-undo").with_files(&["user/a", "staging/b"]);
+        let (user_root, staging, backup) =
+            (tmp.join("user"), tmp.join("staging"), tmp.join("backup"));
+        let mut calls = 0;
+        let error = swap_in(&staging, &user_root, &backup, |from, to| {
+            calls += 1;
+            if calls == 2 {
+                return Err(io::Error::other("disk on fire"));
+            }
+            fs::rename(from, to)
+        })
+        .unwrap_err();
+        assert_eq!(
+            error,
+            format!(
+                "failed to move the restored tree to {}: disk on fire",
+                user_root.display()
+            )
+        );
+        assert_eq!(read(&user_root.join("a")), "user/a");
+        assert!(!staging.exists() && !backup.exists());
+    }
+
+    #[test]
+    fn failed_restore_says_where_the_user_root_went() {
+        let tmp = TempDir::new("snapshot-stranded").with_files(&["user/a", "staging/b"]);
+        let (user_root, staging, backup) =
+            (tmp.join("user"), tmp.join("staging"), tmp.join("backup"));
+        let mut calls = 0;
+        let error = swap_in(&staging, &user_root, &backup, |from, to| {
+            calls += 1;
+            if calls >= 2 {
+                return Err(io::Error::other("disk on fire"));
+            }
+            fs::rename(from, to)
+        })
+        .unwrap_err();
+        assert!(error.ends_with(&format!(
+            "the previous tree is still at {} (disk on fire)",
+            backup.display()
+        )));
+        assert_eq!(read(&backup.join("a")), "user/a");
+    }
+
+    #[test]
+    fn unknown_snapshots_leave_the_user_root_alone() {
+        let tmp = TempDir::new("snapshot-unknown").with_files(&["user/a"]);
+        let (user_root, dir) = (tmp.join("user"), tmp.join("snapshots"));
+        create(&dir, &user_root, Some("one"), false).unwrap();
+        assert!(restore(&dir, "two", &user_root)
+            .unwrap_err()
+            .starts_with("no snapshot 'two'"));
+        assert!(delete(&dir, "two").is_err());
+        assert_eq!(read(&user_root.join("a")), "user/a");
+    }
+
+    #[test]
+    fn checks_names() {
+        assert!(check_name("before-upgrade_2").is_ok());
+        for name in ["", ".hidden", "a/b", "auto-1"] {
+            assert!(check_name(name).is_err(), "{}", name);
+        }
+    }
+}
@@ -0,0 +1,844 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::IsTerminal;
+use std::process::ExitStatus;
+use std::time::{Duration, Instant};
+use tokio::process::Child;
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::sync::mpsc;
+
+use crate::backend::{Backend, ResolvedBoot};
+use crate::duration::HumanDuration;
+use crate::expect::{ExpectAction, Expectation};
+use crate::logging::{self, SharedLog};
+use crate::output::{self, Line};
+
+/// When a finished cozy-os process is started again
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    #[default]
+    Never,
+    /// Restart after a non-zero exit or a signal death
+    OnFailure,
+    /// Restart after every exit
+    Always,
+}
+
+/// The `[supervisor]` section
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SupervisorConfig {
+    pub restart: RestartPolicy,
+    /// Restarts allowed before giving up
+    pub max_restarts: u32,
+    /// Delay before the first restart; doubled after each further restart
+    pub backoff: HumanDuration,
+    /// Upper bound for the doubled delay
+    pub max_backoff: HumanDuration,
+    /// Give up when `crash_loop_restarts` restarts happen within this window
+    pub crash_loop_window: HumanDuration,
+    pub crash_loop_restarts: u32,
+    /// How long the child may take to exit after a forwarded signal before
+    /// it is sent SIGKILL
+    pub grace_period: HumanDuration,
+}
+
+impl Default for SupervisorConfig {
+    fn default() -> Self {
+        SupervisorConfig {
+            restart: RestartPolicy::Never,
+            max_restarts: 5,
+            backoff: Duration::from_secs(1).into(),
+            max_backoff: Duration::from_secs(30).into(),
+            crash_loop_window: Duration::from_secs(30).into(),
+            crash_loop_restarts: 3,
+            grace_period: Duration::from_secs(10).into(),
+        }
+    }
+}
+
+impl SupervisorConfig {
+    // Delay before restart number `restart` (1-based)
+    fn backoff_for(&self, restart: u32) -> Duration {
+        let factor = 2u32.saturating_pow(restart.saturating_sub(1));
+        self.backoff
+            .as_duration()
+            .saturating_mul(factor)
+            .min(self.max_backoff.as_duration())
+    }
+}
+
+/// How one run of the child ended
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Exited(i32),
+    Signaled(i32),
+    SpawnFailed(String),
+}
+
+impl Outcome {
+    pub fn from_status(status: ExitStatus) -> Self {
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::ExitStatusExt;
+            if let Some(signal) = status.signal() {
+                return Outcome::Signaled(signal);
+            }
+        }
+        Outcome::Exited(status.code().unwrap_or(1))
+    }
+
+    pub fn success(&self) -> bool {
+        *self == Outcome::Exited(0)
+    }
+
+    /// Exit code for `boot` itself, using the shell convention of 128 + signal
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Outcome::Exited(code) => *code,
+            Outcome::Signaled(signal) => 128 + signal,
+            Outcome::SpawnFailed(_) => 1,
+        }
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Exited(code) => write!(f, "exited with code {}", code),
+            Outcome::Signaled(signal) => match signal_name(*signal) {
+                Some(name) => write!(f, "killed by signal {} ({})", signal, name),
+                None => write!(f, "killed by signal {}", signal),
+            },
+            Outcome::SpawnFailed(reason) => write!(f, "failed to start: {}", reason),
+        }
+    }
+}
+
+/// One run of the child process
+#[derive(Debug, Clone)]
+pub struct Attempt {
+    /// 1-based attempt number
+    pub number: u32,
+    pub runtime: Duration,
+    pub outcome: Outcome,
+    /// Why `boot` asked the child to stop, if it did
+    pub stopped: Option<StopReason>,
+}
+
+impl Attempt {
+    /// Whether the attempt did what it was for: CozyOS exited cleanly, or
+    /// was stopped because an `exit-success` pattern matched
+    pub fn succeeded(&self) -> bool {
+        self.outcome.success() || self.stopped == Some(StopReason::ExpectSucceeded)
+    }
+}
+
+/// Why supervision stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// The restart policy did not ask for another run
+    Finished,
+    MaxRestarts,
+    CrashLoop,
+    /// `boot` received this signal and shut the child down
+    Interrupted(i32),
+    /// The `timeout` limit was reached
+    TimedOut,
+    /// A required `[[expect]]` pattern did not appear in time
+    NotReady,
+    /// A `fail` pattern appeared
+    ExpectFailed,
+    /// An `exit-success` pattern appeared
+    ExpectSucceeded,
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StopReason::Finished => write!(f, "finished"),
+            StopReason::MaxRestarts => write!(f, "restart limit reached"),
+            StopReason::CrashLoop => write!(f, "crash loop detected"),
+            StopReason::Interrupted(signal) => match signal_name(*signal) {
+                Some(name) => write!(f, "interrupted by {}", name),
+                None => write!(f, "interrupted by signal {}", signal),
+            },
+            StopReason::TimedOut => write!(f, "timed out"),
+            StopReason::NotReady => write!(f, "expected output did not appear in time"),
+            StopReason::ExpectFailed => write!(f, "failure pattern matched"),
+            StopReason::ExpectSucceeded => write!(f, "success pattern matched"),
+        }
+    }
+}
+
+/// Exit code of `boot` when the `timeout` limit stopped the run
+pub const EXIT_TIMED_OUT: i32 = 124;
+
+/// Exit code of `boot` when CozyOS missed its readiness deadline
+pub const EXIT_NOT_READY: i32 = 125;
+
+/// Exit code of `boot` when a `fail` pattern matched
+pub const EXIT_EXPECT_FAILED: i32 = 123;
+
+/// Every attempt made and why supervision ended
+#[derive(Debug, Clone)]
+pub struct Summary {
+    pub attempts: Vec<Attempt>,
+    pub stop: StopReason,
+}
+
+impl Summary {
+    /// The outcome of the last attempt
+    pub fn last_outcome(&self) -> &Outcome {
+        &self
+            .attempts
+            .last()
+            .expect("at least one attempt is always made")
+            .outcome
+    }
+
+    /// Exit code for `boot`: the dedicated codes when a deadline or an
+    /// `[[expect]]` rule stopped the run, 128+N for signal N arriving while
+    /// no attempt was running, otherwise that of the last attempt
+    pub fn exit_code(&self) -> i32 {
+        let last = self
+            .attempts
+            .last()
+            .expect("at least one attempt is always made");
+        match self.stop {
+            // During the restart delay there was no child to pass the signal on to
+            StopReason::Interrupted(signal)
+                if !matches!(last.stopped, Some(StopReason::Interrupted(_))) =>
+            {
+                128 + signal
+            }
+            StopReason::TimedOut => EXIT_TIMED_OUT,
+            StopReason::NotReady => EXIT_NOT_READY,
+            StopReason::ExpectFailed => EXIT_EXPECT_FAILED,
+            StopReason::ExpectSucceeded => 0,
+            _ => self.last_outcome().exit_code(),
+        }
+    }
+}
+
+/// Progress reported while supervising
+#[derive(Debug)]
+pub enum Event<'a> {
+    Started {
+        attempt: u32,
+    },
+    Finished(&'a Attempt),
+    Restarting {
+        attempt: u32,
+        delay: Duration,
+    },
+    GivingUp(StopReason),
+    /// A signal received by `boot` was passed on to the child
+    Forwarded {
+        signal: i32,
+    },
+    /// The child outlived the grace period and is being sent SIGKILL
+    Killing {
+        grace_period: Duration,
+    },
+    /// An `[[expect]]` pattern appeared in the output
+    Matched {
+        pattern: &'a str,
+        action: ExpectAction,
+        after: Duration,
+    },
+    /// The `timeout` limit was reached; the child is being stopped
+    TimedOut {
+        timeout: Duration,
+    },
+    /// A required pattern did not appear in time; the child is being stopped
+    Missed {
+        pattern: &'a str,
+        within: Duration,
+    },
+}
+
+/// Name of the common signals, for messages
+pub fn signal_name(signal: i32) -> Option<&'static str> {
+    match signal {
+        libc::SIGHUP => Some("SIGHUP"),
+        libc::SIGINT => Some("SIGINT"),
+        libc::SIGQUIT => Some("SIGQUIT"),
+        libc::SIGABRT => Some("SIGABRT"),
+        libc::SIGKILL => Some("SIGKILL"),
+        libc::SIGSEGV => Some("SIGSEGV"),
+        libc::SIGPIPE => Some("SIGPIPE"),
+        libc::SIGTERM => Some("SIGTERM"),
+        _ => None,
+    }
+}
+
+/// The shutdown signals `boot` forwards to the child: SIGINT, SIGTERM and SIGHUP.
+/// Once installed they no longer terminate `boot` itself.
+pub struct ShutdownSignals {
+    interrupt: Signal,
+    terminate: Signal,
+    hangup: Signal,
+}
+
+impl ShutdownSignals {
+    pub fn install() -> Result<Self, String> {
+        let install = |kind: SignalKind| {
+            signal(kind).map_err(|e| format!("failed to install signal handler: {}", e))
+        };
+        Ok(ShutdownSignals {
+            interrupt: install(SignalKind::interrupt())?,
+            terminate: install(SignalKind::terminate())?,
+            hangup: install(SignalKind::hangup())?,
+        })
+    }
+
+    /// Wait for the next shutdown signal and return its number
+    pub async fn recv(&mut self) -> i32 {
+        tokio::select! {
+            _ = self.interrupt.recv() => libc::SIGINT,
+            _ = self.terminate.recv() => libc::SIGTERM,
+            _ = self.hangup.recv() => libc::SIGHUP,
+        }
+    }
+}
+
+/// Deadlines and output handling for a supervised run
+#[derive(Debug, Clone, Default)]
+pub struct RunOptions {
+    /// Wall-clock limit for the whole run, restarts included
+    pub timeout: Option<Duration>,
+    /// Patterns every attempt's output is checked against
+    pub expect: Vec<Expectation>,
+    /// Log that receives the child's output and the supervisor's events
+    pub log: Option<SharedLog>,
+}
+
+impl RunOptions {
+    // The child's output is piped only when something reads it
+    fn captures_output(&self) -> bool {
+        !self.expect.is_empty() || self.log.is_some()
+    }
+
+    // Record a supervisor event in the boot log
+    fn log(&self, text: &str) {
+        logging::write_shared(self.log.as_ref(), "boot", text);
+    }
+}
+
+// Sleep until `at`, or forever when there is no deadline
+async fn until(at: Option<tokio::time::Instant>) {
+    match at {
+        Some(at) => tokio::time::sleep_until(at).await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn next_line(lines: &mut Option<mpsc::UnboundedReceiver<Line>>) -> Option<Line> {
+    match lines {
+        Some(lines) => lines.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Whether a child should be started as the leader of a new process group,
+/// so it can be signalled together with everything it starts. Not while
+/// stdin is a terminal: outside the terminal's foreground group, the child
+/// would be stopped with SIGTTIN as soon as it read from it.
+pub fn use_process_group() -> bool {
+    !std::io::stdin().is_terminal()
+}
+
+// Whether `pid` was started as the leader of its own process group
+fn leads_group(pid: u32) -> bool {
+    // SAFETY: getpgid has no memory-safety preconditions
+    unsafe { libc::getpgid(pid as libc::pid_t) == pid as libc::pid_t }
+}
+
+/// Signal the process group led by `pid`, or only `pid` when it was started
+/// without one (see [`use_process_group`])
+pub fn signal_process(pid: u32, signal: i32) {
+    let target = if leads_group(pid) {
+        -(pid as libc::pid_t)
+    } else {
+        pid as libc::pid_t
+    };
+    // SAFETY: kill has no memory-safety preconditions; the pid belongs to a
+    // child that has not been reaped yet
+    unsafe { libc::kill(target, signal) };
+}
+
+fn send_signal(child: &Child, signal: i32) {
+    if let Some(pid) = child.id() {
+        signal_process(pid, signal);
+    }
+}
+
+// Once a child asked to stop has exited, give what is left of its process
+// group until `kill_at` to exit as well, then kill it
+async fn stop_group(pgid: u32, kill_at: tokio::time::Instant) {
+    // SAFETY: kill has no memory-safety preconditions; signal 0 only checks
+    // whether any process in the group is left
+    let alive = || unsafe { libc::kill(-(pgid as libc::pid_t), 0) == 0 };
+    while alive() && tokio::time::Instant::now() < kill_at {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    if alive() {
+        // SAFETY: as above
+        unsafe { libc::kill(-(pgid as libc::pid_t), libc::SIGKILL) };
+    }
+}
+
+// Wait for one attempt to finish. Shutdown signals are forwarded; the
+// timeout, a missed `[[expect]]` deadline or a `fail` / `exit-success` match
+// sends SIGTERM. Once the child has
+// been asked to stop, it gets SIGKILL after the grace period. Returns the
+// outcome and why the child was asked to stop, if it was.
+async fn wait_attempt(
+    child: &mut Child,
+    signals: &mut ShutdownSignals,
+    config: &SupervisorConfig,
+    options: &RunOptions,
+    deadline: Option<tokio::time::Instant>,
+    on_event: &mut impl FnMut(Event<'_>),
+) -> (Outcome, Option<StopReason>) {
+    let started = tokio::time::Instant::now();
+    let grace_period = config.grace_period.as_duration();
+    let pgid = child.id().filter(|&pid| leads_group(pid));
+    let mut lines = options
+        .captures_output()
+        .then(|| output::capture(child, options.log.clone()));
+    let mut matched = vec![false; options.expect.len()];
+    let mut stop = None;
+    let mut kill_at = None;
+
+    // Ask the child to stop with SIGTERM, unless it already was
+    let terminate = |child: &Child,
+                     stop: &mut Option<StopReason>,
+                     reason: StopReason|
+     -> Option<tokio::time::Instant> {
+        if stop.is_some() {
+            return None;
+        }
+        send_signal(child, libc::SIGTERM);
+        *stop = Some(reason);
+        tokio::time::Instant::now().checked_add(grace_period)
+    };
+
+    loop {
+        // The earliest deadline of a required pattern that has not appeared
+        let missing = options
+            .expect
+            .iter()
+            .enumerate()
+            .filter(|(idx, rule)| !matched[*idx] && rule.is_required())
+            .filter_map(|(idx, rule)| {
+                rule.timeout
+                    .and_then(|timeout| started.checked_add(timeout))
+                    .map(|at| (at, idx))
+            })
+            .min();
+
+        tokio::select! {
+            status = child.wait() => {
+                let outcome = match status {
+                    Ok(status) => Outcome::from_status(status),
+                    Err(e) => Outcome::SpawnFailed(e.to_string()),
+                };
+                if let (Some(pgid), Some(kill_at)) = (pgid, kill_at) {
+                    stop_group(pgid, kill_at).await;
+                }
+                // Let the last lines reach the terminal, without waiting on
+                // grandchildren that still hold the pipes open
+                if let Some(mut lines) = lines {
+                    let _ = tokio::time::timeout(Duration::from_millis(500), async {
+                        while lines.recv().await.is_some() {}
+                    }).await;
+                }
+                return (outcome, stop);
+            }
+            line = next_line(&mut lines) => {
+                let Some(line) = line else {
+                    lines = None;
+                    continue;
+                };
+                for (idx, rule) in options.expect.iter().enumerate() {
+                    let watching = rule.action != ExpectAction::Fail
+                        || rule.timeout.is_none_or(|timeout| started.elapsed() <= timeout);
+                    if matched[idx] || !watching || !rule.stream.accepts(line.stream) || !rule.pattern.is_match(&line.text) {
+                        continue;
+                    }
+                    matched[idx] = true;
+                    on_event(Event::Matched { pattern: rule.pattern.as_str(), action: rule.action, after: started.elapsed() });
+                    let reason = match rule.action {
+                        ExpectAction::Ready => continue,
+                        ExpectAction::Fail => StopReason::ExpectFailed,
+                        ExpectAction::ExitSuccess => StopReason::ExpectSucceeded,
+                    };
+                    kill_at = terminate(child, &mut stop, reason).or(kill_at);
+                }
+            }
+            signal = signals.recv() => {
+                send_signal(child, signal);
+                on_event(Event::Forwarded { signal });
+                if stop.is_none() {
+                    stop = Some(StopReason::Interrupted(signal));
+                    kill_at = tokio::time::Instant::now().checked_add(grace_period);
+                }
+            }
+            _ = until(deadline), if stop.is_none() => {
+                on_event(Event::TimedOut { timeout: options.timeout.unwrap_or_default() });
+                kill_at = terminate(child, &mut stop, StopReason::TimedOut);
+            }
+            _ = until(missing.map(|(at, _)| at)), if stop.is_none() => {
+                let (at, idx) = missing.expect("branch only runs with a deadline");
+                matched[idx] = true;
+                on_event(Event::Missed { pattern: options.expect[idx].pattern.as_str(), within: at - started });
+                kill_at = terminate(child, &mut stop, StopReason::NotReady);
+            }
+            _ = until(kill_at) => {
+                on_event(Event::Killing { grace_period });
+                send_signal(child, libc::SIGKILL);
+                kill_at = None;
+            }
+        }
+    }
+}
+
+/// Launch `boot` and keep restarting it according to `config`
+pub async fn supervise(
+    backend: &dyn Backend,
+    boot: &ResolvedBoot,
+    config: &SupervisorConfig,
+    options: &RunOptions,
+    mut on_event: impl FnMut(Event<'_>),
+) -> Summary {
+    let mut attempts: Vec<Attempt> = Vec::new();
+    let mut restart_times: Vec<Instant> = Vec::new();
+    // A timeout too long to represent is no timeout at all
+    let deadline = options
+        .timeout
+        .and_then(|timeout| tokio::time::Instant::now().checked_add(timeout));
+    let mut signals = match ShutdownSignals::install() {
+        Ok(signals) => signals,
+        Err(e) => {
+            let attempt = Attempt {
+                number: 1,
+                runtime: Duration::ZERO,
+                outcome: Outcome::SpawnFailed(e),
+                stopped: None,
+            };
+            on_event(Event::Finished(&attempt));
+            return Summary {
+                attempts: vec![attempt],
+                stop: StopReason::Finished,
+            };
+        }
+    };
+
+    loop {
+        let number = attempts.len() as u32 + 1;
+        on_event(Event::Started { attempt: number });
+        options.log(&format!("attempt {} started", number));
+
+        let started = Instant::now();
+        let (outcome, stopped) = match backend.launch(boot, options.captures_output()) {
+            Ok(mut child) => {
+                wait_attempt(
+                    &mut child,
+                    &mut signals,
+                    config,
+                    options,
+                    deadline,
+                    &mut on_event,
+                )
+                .await
+            }
+            Err(e) => (Outcome::SpawnFailed(e), None),
+        };
+        attempts.push(Attempt {
+            number,
+            runtime: started.elapsed(),
+            outcome,
+            stopped,
+        });
+        let attempt = attempts.last().expect("just pushed");
+        options.log(&format!(
+            "attempt {} {} after {:.1}s",
+            number,
+            attempt.outcome,
+            attempt.runtime.as_secs_f64()
+        ));
+        on_event(Event::Finished(attempt));
+
+        let wants_restart = match config.restart {
+            RestartPolicy::Never => false,
+            RestartPolicy::OnFailure => !attempt.outcome.success(),
+            RestartPolicy::Always => true,
+        };
+        // A binary that cannot even be started will not start on a retry either
+        let stop = if stopped.is_some() {
+            stopped
+        } else if !wants_restart || matches!(attempt.outcome, Outcome::SpawnFailed(_)) {
+            Some(StopReason::Finished)
+        } else if restart_times.len() as u32 >= config.max_restarts {
+            Some(StopReason::MaxRestarts)
+        } else {
+            let window = config.crash_loop_window.as_duration();
+            let recent = restart_times
+                .iter()
+                .filter(|time| time.elapsed() < window)
+                .count() as u32;
+            (config.crash_loop_restarts > 0 && recent + 1 > config.crash_loop_restarts)
+                .then_some(StopReason::CrashLoop)
+        };
+
+        if let Some(stop) = stop {
+            options.log(&format!("stopped: {}", stop));
+            if matches!(stop, StopReason::MaxRestarts | StopReason::CrashLoop) {
+                on_event(Event::GivingUp(stop));
+            }
+            return Summary { attempts, stop };
+        }
+
+        let delay = config.backoff_for(restart_times.len() as u32 + 1);
+        on_event(Event::Restarting {
+            attempt: number + 1,
+            delay,
+        });
+        // A shutdown signal or the timeout during the backoff cancels the restart
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            signal = signals.recv() => {
+                return Summary { attempts, stop: StopReason::Interrupted(signal) };
+            }
+            _ = until(deadline) => {
+                on_event(Event::TimedOut { timeout: options.timeout.unwrap_or_default() });
+                return Summary { attempts, stop: StopReason::TimedOut };
+            }
+        }
+        restart_times.push(Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(outcomes: &[(Outcome, Option<StopReason>)], stop: StopReason) -> Summary {
+        let attempts = outcomes
+            .iter()
+            .enumerate()
+            .map(|(idx, (outcome, stopped))| Attempt {
+                number: idx as u32 + 1,
+                runtime: Duration::ZERO,
+                outcome: outcome.clone(),
+                stopped: *stopped,
+            })
+            .collect();
+        Summary { attempts, stop }
+    }
+
+    #[test]
+    fn exit_code_of_the_last_attempt() {
+        assert_eq!(
+            summary(&[(Outcome::Exited(3), None)], StopReason::Finished).exit_code(),
+            3
+        );
+        assert_eq!(
+            summary(
+                &[(Outcome::Exited(1), None), (Outcome::Signaled(9), None)],
+                StopReason::MaxRestarts
+            )
+            .exit_code(),
+            137
+        );
+        assert_eq!(
+            summary(
+                &[(Outcome::SpawnFailed("no".into()), None)],
+                StopReason::Finished
+            )
+            .exit_code(),
+            1
+        );
+    }
+
+    #[test]
+    fn dedicated_exit_codes() {
+        let stopped = |reason| {
+            summary(&[(Outcome::Signaled(libc::SIGTERM), Some(reason))], reason).exit_code()
+        };
+        assert_eq!(stopped(StopReason::TimedOut), EXIT_TIMED_OUT);
+        assert_eq!(stopped(StopReason::NotReady), EXIT_NOT_READY);
+        assert_eq!(stopped(StopReason::ExpectFailed), EXIT_EXPECT_FAILED);
+        assert_eq!(stopped(StopReason::ExpectSucceeded), 0);
+    }
+
+    #[test]
+    fn interrupted_attempt_keeps_its_own_code() {
+        let interrupted = StopReason::Interrupted(libc::SIGINT);
+        assert_eq!(
+            summary(&[(Outcome::Exited(0), Some(interrupted))], interrupted).exit_code(),
+            0
+        );
+        assert_eq!(
+            summary(
+                &[(Outcome::Signaled(libc::SIGINT), Some(interrupted))],
+                interrupted
+            )
+            .exit_code(),
+            130
+        );
+    }
+
+    #[test]
+    fn interrupted_during_backoff() {
+        let summary = summary(
+            &[(Outcome::Exited(0), None)],
+            StopReason::Interrupted(libc::SIGTERM),
+        );
+        assert_eq!(summary.exit_code(), 143);
+    }
+
+    #[test]
+    fn exit_success_stop_counts_as_success() {
+        let attempt = Attempt {
+            number: 1,
+            runtime: Duration::ZERO,
+            outcome: Outcome::Signaled(libc::SIGTERM),
+            stopped: Some(StopReason::ExpectSucceeded),
+        };
+        assert!(attempt.succeeded());
+        assert!(!Attempt {
+            stopped: Some(StopReason::TimedOut),
+            ..attempt
+        }
+        .succeeded());
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_limit() {
+        let config = SupervisorConfig {
+            backoff: HumanDuration(Duration::from_secs(1)),
+            max_backoff: HumanDuration(Duration::from_secs(5)),
+            ..SupervisorConfig::default()
+        };
+        let delays: Vec<u64> = (1..=5)
+            .map(|restart| config.backoff_for(restart).as_secs())
+            .collect();
+        assert_eq!(delays, [1, 2, 4, 5, 5]);
+    }
+
+    #[test]
+    fn signals_the_whole_group_of_a_group_leader() {
+        use std::io::BufRead;
+        use std::os::unix::process::CommandExt;
+
+        let mut shell = std::process::Command::new("sh")
+            .args(["-c", "sleep 30 & echo $!; wait"])
+            .process_group(0)
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut line = String::new();
+        std::io::BufReader::new(shell.stdout.take().unwrap())
+            .read_line(&mut line)
+            .unwrap();
+        let sleep: u32 = line.trim().parse().unwrap();
+
+        signal_process(shell.id(), libc::SIGKILL);
+        shell.wait().unwrap();
+        assert!(crate::test_support::exited(sleep));
+    }
+
+    #[test]
+    fn signals_only_the_child_outside_a_group() {
+        use std::os::unix::process::ExitStatusExt;
+
+        // Shares the test's process group, which must not be signalled
+        let mut sleep = std::process::Command::new("sleep")
+            .arg("30")
+            .spawn()
+            .unwrap();
+        signal_process(sleep.id(), libc::SIGKILL);
+        assert_eq!(sleep.wait().unwrap().signal(), Some(libc::SIGKILL));
+    }
+
+    // Runs a shell script in place of cozy-os
+    struct Script(&'static str);
+
+    impl Backend for Script {
+        fn name(&self) -> &'static str {
+            "script"
+        }
+
+        fn plan(&self, _boot: &ResolvedBoot) -> Result<crate::BootPlan, String> {
+            Ok(crate::BootPlan::builder("sh").args(["-c", self.0]).build())
+        }
+    }
+
+    fn boot() -> ResolvedBoot {
+        ResolvedBoot {
+            executable: "sh".into(),
+            kern_root: String::new(),
+            user_root: String::new(),
+            bootargs: Vec::new(),
+            mounts: Vec::new(),
+            allow_32bit: None,
+            allow_universal: None,
+            allow_64bit: None,
+            boot_string: None,
+            debug: false,
+        }
+    }
+
+    fn expect(pattern: &str, action: ExpectAction, timeout: Option<Duration>) -> Expectation {
+        Expectation {
+            pattern: regex::Regex::new(pattern).unwrap(),
+            stream: crate::expect::StreamFilter::Both,
+            action,
+            timeout,
+        }
+    }
+
+    async fn run(script: &'static str, expect: Vec<Expectation>) -> Summary {
+        let options = RunOptions {
+            expect,
+            ..RunOptions::default()
+        };
+        supervise(
+            &Script(script),
+            &boot(),
+            &SupervisorConfig::default(),
+            &options,
+            |_| {},
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn ready_prompts_match_without_a_newline() {
+        let ready = expect("login:", ExpectAction::Ready, Some(Duration::from_secs(1)));
+        let summary = run("printf 'cozy login: '; sleep 2", vec![ready]).await;
+        assert_eq!(summary.stop, StopReason::Finished);
+        assert_eq!(summary.exit_code(), 0);
+    }
+
+    #[tokio::test]
+    async fn expect_rules_match_unfinished_lines() {
+        let password = expect("Password:", ExpectAction::ExitSuccess, None);
+        let summary = run("printf 'Password: '; exec sleep 30", vec![password]).await;
+        assert_eq!(summary.stop, StopReason::ExpectSucceeded);
+        assert_eq!(summary.exit_code(), 0);
+
+        let panic = expect("(?i)kernel panic", ExpectAction::Fail, None);
+        let summary = run(
+            "printf 'Kernel panic - not syncing'; exec sleep 30",
+            vec![panic],
+        )
+        .await;
+        assert_eq!(summary.stop, StopReason::ExpectFailed);
+        assert_eq!(summary.exit_code(), 123);
+    }
+}
@@ -0,0 +1,66 @@
+//! Fixtures shared by the unit tests
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+/// A fresh directory under the system temp dir that is removed when dropped,
+/// so a failing test does not leave it behind
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// `name` only makes leftovers from a killed test run recognisable
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!(
+            "cozyboot-test-{}-{}-{}",
+            name,
+            std::process::id(),
+            NEXT_DIR.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = crate::user_root::remove_tree(&path);
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn join(&self, path: impl AsRef<Path>) -> PathBuf {
+        self.0.join(path)
+    }
+
+    /// Create `files` (relative paths, parents included), each holding its own name
+    pub fn with_files(self, files: &[&str]) -> Self {
+        for file in files {
+            let path = self.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, file).unwrap();
+        }
+        self
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = crate::user_root::remove_tree(&self.0);
+    }
+}
+
+/// Wait up to 5s for `pid` to be gone, or a zombie nobody has reaped yet
+pub fn exited(pid: u32) -> bool {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let status = fs::read_to_string(format!("/proc/{}/status", pid)).unwrap_or_default();
+        if status.is_empty() || status.contains("State:\tZ") {
+            return true;
+        }
+        if Instant::now() > deadline {
+            return false;
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+}
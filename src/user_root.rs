@@ -0,0 +1,226 @@
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path};
+
+/// Whether this process may create files in `dir`
+pub fn is_writable(dir: &Path) -> bool {
+    let Ok(path) = CString::new(dir.as_os_str().as_bytes()) else {
+        return false;
+    };
+    // SAFETY: `path` is a valid NUL-terminated string for the duration of the call
+    unsafe { libc::access(path.as_ptr(), libc::W_OK) == 0 }
+}
+
+/// Check a `user_skeleton` entry is a relative path that stays inside user_root
+pub fn check_skeleton_entry(entry: &str) -> Result<(), String> {
+    let path = Path::new(entry);
+    if entry.trim().is_empty() {
+        return Err("user_skeleton has an empty entry".to_string());
+    }
+    if path.is_absolute()
+        || path
+            .components()
+            .any(|component| component == Component::ParentDir)
+    {
+        return Err(format!(
+            "user_skeleton entry '{}' must be a path inside user_root",
+            entry
+        ));
+    }
+    Ok(())
+}
+
+/// Skeleton directories missing from `user_root`
+pub fn missing_skeleton<'a>(user_root: &Path, skeleton: &'a [String]) -> Vec<&'a str> {
+    skeleton
+        .iter()
+        .filter(|entry| !user_root.join(entry).is_dir())
+        .map(String::as_str)
+        .collect()
+}
+
+/// Create `user_root` from `template` and add the skeleton directories.
+/// The tree is copied next to `user_root` first and renamed into place, so an
+/// interrupted copy never leaves a half-provisioned user root behind.
+/// Returns the number of files copied.
+pub fn provision(user_root: &Path, template: &Path, skeleton: &[String]) -> Result<u64, String> {
+    if !template.is_dir() {
+        return Err(format!(
+            "user_root_template '{}' is not a directory",
+            template.display()
+        ));
+    }
+    let name = user_root
+        .file_name()
+        .ok_or_else(|| format!("cannot provision user root '{}'", user_root.display()))?;
+    let staging = user_root.with_file_name(format!(
+        ".{}.provisioning-{}",
+        name.to_string_lossy(),
+        std::process::id()
+    ));
+    if let Some(parent) = user_root
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        fs::create_dir_all(parent)
+            .map_err(|e| format!("failed to create {}: {}", parent.display(), e))?;
+    }
+
+    let result = copy_tree(template, &staging).and_then(|copied| {
+        for entry in skeleton {
+            fs::create_dir_all(staging.join(entry))?;
+        }
+        fs::rename(&staging, user_root)?;
+        Ok(copied)
+    });
+    result.map_err(|e| {
+        let _ = remove_tree(&staging);
+        format!(
+            "failed to create user root '{}' from '{}': {}",
+            user_root.display(),
+            template.display(),
+            e
+        )
+    })
+}
+
+/// Copy the directory tree at `from` to the new directory `to`, keeping
+/// permissions, modification times and symlinks. Returns the number of files copied.
+pub fn copy_tree(from: &Path, to: &Path) -> io::Result<u64> {
+    fs::create_dir(to)?;
+    let mut copied = 0;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let (source, target) = (entry.path(), to.join(entry.file_name()));
+        let kind = entry.file_type()?;
+        if kind.is_dir() {
+            copied += copy_tree(&source, &target)?;
+        } else if kind.is_symlink() {
+            std::os::unix::fs::symlink(fs::read_link(&source)?, &target)?;
+        } else {
+            fs::copy(&source, &target)?;
+            fs::File::open(&target)?.set_modified(entry.metadata()?.modified()?)?;
+            copied += 1;
+        }
+    }
+    // Last, so a read-only directory can still be filled
+    fs::set_permissions(to, fs::metadata(from)?.permissions())?;
+    Ok(copied)
+}
+
+/// Delete the directory tree at `path`. Directories copied from a read-only
+/// source keep their mode, so they are made writable first; otherwise their
+/// entries could not be removed.
+pub fn remove_tree(path: &Path) -> io::Result<()> {
+    make_writable(path)?;
+    fs::remove_dir_all(path)
+}
+
+fn make_writable(dir: &Path) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(dir)?;
+    if !metadata.is_dir() {
+        return Ok(());
+    }
+    let mut permissions = metadata.permissions();
+    if permissions.mode() & 0o700 != 0o700 {
+        permissions.set_mode(permissions.mode() | 0o700);
+        fs::set_permissions(dir, permissions)?;
+    }
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            make_writable(&entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+    use std::time::{Duration, SystemTime};
+
+    fn names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn provisions_from_the_template() {
+        let tmp = TempDir::new("provision").with_files(&["template/etc/motd", "template/bin/sh"]);
+        let template = tmp.join("template");
+        std::os::unix::fs::symlink("etc/motd", template.join("motd")).unwrap();
+        let old = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        fs::File::open(template.join("bin/sh"))
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+        fs::set_permissions(template.join("bin"), fs::Permissions::from_mode(0o555)).unwrap();
+
+        let user_root = tmp.join("users/cozy");
+        let skeleton = ["home/cozy".to_string(), "etc".to_string()];
+        assert_eq!(provision(&user_root, &template, &skeleton).unwrap(), 2);
+
+        assert_eq!(names(&user_root), ["bin", "etc", "home", "motd"]);
+        assert!(missing_skeleton(&user_root, &skeleton).is_empty());
+        assert_eq!(
+            fs::read_link(user_root.join("motd")).unwrap(),
+            Path::new("etc/motd")
+        );
+        let sh = fs::metadata(user_root.join("bin/sh")).unwrap();
+        assert_eq!(sh.modified().unwrap(), old);
+        let bin = fs::metadata(user_root.join("bin")).unwrap();
+        assert_eq!(bin.permissions().mode() & 0o777, 0o555);
+        // Nothing is left beside the new user root
+        assert_eq!(names(&tmp.join("users")), ["cozy"]);
+    }
+
+    #[test]
+    fn failed_provisioning_leaves_nothing_behind() {
+        let tmp = TempDir::new("provision-fail").with_files(&["template/a", "user/b"]);
+        let (user_root, template) = (tmp.join("user"), tmp.join("template"));
+        let error = provision(&user_root, &template, &[]).unwrap_err();
+        assert!(error.starts_with("failed to create user root"), "{}", error);
+        assert_eq!(names(tmp.path()), ["template", "user"]);
+        assert_eq!(names(&user_root), ["b"]);
+
+        let error = provision(&tmp.join("new"), &tmp.join("missing"), &[]).unwrap_err();
+        assert!(error.ends_with("is not a directory"), "{}", error);
+        assert!(!tmp.join("new").exists());
+    }
+
+    #[test]
+    fn lists_missing_skeleton_directories() {
+        let tmp = TempDir::new("skeleton").with_files(&["home/cozy/notes", "tmp"]);
+        let skeleton = [
+            "home/cozy".to_string(),
+            "tmp".to_string(),
+            "var".to_string(),
+        ];
+        assert_eq!(missing_skeleton(tmp.path(), &skeleton), ["tmp", "var"]);
+    }
+
+    #[test]
+    fn checks_skeleton_entries() {
+        assert!(check_skeleton_entry("home/cozy").is_ok());
+        for entry in ["", " ", "/home", "home/../.."] {
+            assert!(check_skeleton_entry(entry).is_err(), "{:?}", entry);
+        }
+    }
+
+    #[test]
+    fn removes_read_only_trees() {
+        let tmp = TempDir::new("remove").with_files(&["tree/locked/file"]);
+        fs::set_permissions(tmp.join("tree/locked"), fs::Permissions::from_mode(0o500)).unwrap();
+        remove_tree(&tmp.join("tree")).unwrap();
+        assert!(!tmp.join("tree").exists());
+    }
+}
@@ -0,0 +1,592 @@
+use std::path::Path;
+
+use crate::backend;
+use crate::bootargs;
+use crate::config::{resolve_mounts, CozyBootConfig, LoadedConfig};
+use crate::diagnostics::{Diagnostic, Location};
+use crate::hooks::Phase;
+use crate::runtime::{self, Version};
+use crate::snapshot;
+use crate::supervisor::RestartPolicy;
+use crate::user_root;
+use crate::vars::{expand_variables, Variables};
+
+/// The outcome of validating the loaded config
+#[derive(Debug)]
+pub struct Validation {
+    /// The typed config, if the merged table could be deserialized at all
+    pub config: Option<CozyBootConfig>,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl Validation {
+    pub fn has_errors(&self) -> bool {
+        self.diagnostics.iter().any(Diagnostic::is_error)
+    }
+}
+
+/// Check the loaded config, collecting every problem instead of stopping at the first
+pub fn validate(loaded: &LoadedConfig) -> Validation {
+    let mut checker = Checker {
+        loaded,
+        diagnostics: Vec::new(),
+    };
+    let config = checker.deserialize();
+    if let Some(config) = &config {
+        checker.check(config);
+    }
+    Validation {
+        config,
+        diagnostics: checker.diagnostics,
+    }
+}
+
+struct Checker<'a> {
+    loaded: &'a LoadedConfig,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Checker<'_> {
+    fn locate(&self, path: &[&str]) -> Option<Location> {
+        let path: Vec<String> = path.iter().map(|part| part.to_string()).collect();
+        self.loaded.merged.locate(&self.loaded.layers, &path)
+    }
+
+    fn error(&mut self, path: &[&str], message: impl Into<String>, help: impl Into<String>) {
+        let diagnostic = Diagnostic::error(message)
+            .at(self.locate(path))
+            .with_help(help);
+        self.diagnostics.push(diagnostic);
+    }
+
+    fn warning(&mut self, path: &[&str], message: impl Into<String>, help: impl Into<String>) {
+        let diagnostic = Diagnostic::warning(message)
+            .at(self.locate(path))
+            .with_help(help);
+        self.diagnostics.push(diagnostic);
+    }
+
+    // Deserialize the merged table, reporting type errors and unknown keys
+    fn deserialize(&mut self) -> Option<CozyBootConfig> {
+        let mut unknown: Vec<String> = Vec::new();
+        let value = toml::Value::Table(self.loaded.merged.table.clone());
+        let mut track_unknown = |path: serde_ignored::Path| unknown.push(path.to_string());
+        let deserializer = serde_ignored::Deserializer::new(value, &mut track_unknown);
+        let result: Result<CozyBootConfig, _> = serde_path_to_error::deserialize(deserializer);
+
+        for key in unknown {
+            let path: Vec<&str> = key.split('.').filter(|part| *part != "?").collect();
+            self.warning(
+                &path,
+                format!("unknown key '{}'", path.join(".")),
+                "remove it or check the spelling; it is ignored",
+            );
+        }
+
+        match result {
+            Ok(config) => Some(config),
+            Err(e) => {
+                let path: Vec<String> = e
+                    .path()
+                    .iter()
+                    .filter_map(|segment| match segment {
+                        serde_path_to_error::Segment::Map { key } => Some(key.clone()),
+                        serde_path_to_error::Segment::Seq { index } => Some(index.to_string()),
+                        _ => None,
+                    })
+                    .collect();
+                let path: Vec<&str> = path.iter().map(String::as_str).collect();
+                // toml appends the key path on a second line; it is already in our message
+                let inner = e.inner().to_string();
+                let reason = inner.lines().next().unwrap_or_default();
+                let message = if path.is_empty() {
+                    format!("invalid configuration: {}", reason)
+                } else {
+                    format!("invalid value for '{}': {}", path.join("."), reason)
+                };
+                self.error(
+                    &path,
+                    message,
+                    "compare with the sections in default_config.toml (`boot init --path <file>`)",
+                );
+                None
+            }
+        }
+    }
+
+    fn check(&mut self, config: &CozyBootConfig) {
+        let vars = Variables::new(
+            &config.vars,
+            &config.main.kern_root,
+            &self.loaded.config_path(),
+        );
+
+        // Variables are checked on their own so unused broken ones still surface
+        let mut names: Vec<&String> = config.vars.keys().collect();
+        names.sort();
+        for name in names {
+            if let Err(e) = vars.expand(&format!("$({})", name)) {
+                self.warning(
+                    &["vars", name],
+                    format!("variable '{}' cannot be expanded: {}", name, e),
+                    "define the missing variable or break the cycle",
+                );
+            }
+        }
+
+        self.check_roots(config, &vars);
+
+        // At least one binary format has to be allowed for anything to run
+        let bin = &config.bin;
+        if [bin.allow_32bit, bin.allow_64bit, bin.allow_universal]
+            .iter()
+            .all(|flag| *flag == Some(false))
+        {
+            self.error(
+                &["bin"],
+                "every allow_* flag in [bin] is false, so no binary could run",
+                "set at least one of allow_32bit, allow_64bit or allow_universal to true",
+            );
+        }
+
+        if let Err(errors) = resolve_mounts(&config.mounts, &vars) {
+            for error in errors {
+                let index = error.index.to_string();
+                self.error(&["mounts", &index, error.field], error.message,
+                    "point host_path at an existing directory and keep guest paths absolute and disjoint, with no ':' in either");
+            }
+        }
+
+        self.check_runtime(config, &vars);
+        self.check_supervisor(config);
+        self.check_logging(config, &vars);
+        self.check_build(config, &vars);
+
+        for phase in [Phase::PreBoot, Phase::PostBoot, Phase::OnFailure] {
+            for (index, hook) in config.hooks.for_phase(phase).iter().enumerate() {
+                let (key, index) = (phase.to_string(), index.to_string());
+                if hook.command().trim().is_empty() {
+                    self.error(
+                        &["hooks", &key, &index],
+                        format!("hooks.{}[{}] is an empty command", key, index),
+                        "remove the entry or give it a shell command",
+                    );
+                } else if let Err(e) = vars.expand(hook.command()) {
+                    self.error(&["hooks", &key, &index], format!("failed to expand hooks.{}[{}]: {}", key, index, e),
+                        "write $$(...) for shell command substitution, or define the variable in [vars]");
+                }
+            }
+        }
+
+        for (index, rule) in config.expect.iter().enumerate() {
+            if let Err(e) = rule.compile() {
+                let index = index.to_string();
+                self.error(
+                    &["expect", &index, "pattern"],
+                    e,
+                    "escape regex metacharacters such as ( [ . * with a backslash",
+                );
+            }
+        }
+
+        for (key, arg) in bootargs::ordered(&config.bootargs) {
+            if let Err(e) = arg.render(key, &|value| vars.expand(value)) {
+                self.error(
+                    &["bootargs", key],
+                    format!("failed to expand bootargs.{}: {}", key, e),
+                    "define the variable in [vars] or give it a default with $(name:-default)",
+                );
+            }
+        }
+    }
+
+    fn check_roots(&mut self, config: &CozyBootConfig, vars: &Variables) {
+        if config.main.kern_root.trim().is_empty() {
+            self.error(
+                &["main", "kern_root"],
+                "main.kern_root is empty",
+                "set it to the kernel directory, e.g. \"$(devroot)/System/kern\"",
+            );
+        } else {
+            match expand_variables(&config.main.kern_root, vars) {
+                // With [build] the kernel may only appear once it has been built
+                Ok(kern_root) if !Path::new(&kern_root).exists() && config.build.is_some() => {
+                    self.warning(
+                        &["main", "kern_root"],
+                        format!(
+                            "OS path '{}' does not exist yet (expanded from '{}')",
+                            kern_root, config.main.kern_root
+                        ),
+                        "[build] will run before booting; check that it creates this directory",
+                    );
+                }
+                Ok(kern_root) if !Path::new(&kern_root).exists() => {
+                    self.error(
+                        &["main", "kern_root"],
+                        format!(
+                            "OS path '{}' does not exist (expanded from '{}')",
+                            kern_root, config.main.kern_root
+                        ),
+                        "build the kernel first or fix the path",
+                    );
+                }
+                Ok(_) => {}
+                Err(e) => self.error(
+                    &["main", "kern_root"],
+                    format!("failed to expand main.kern_root: {}", e),
+                    "define the variable in [vars] or give it a default with $(name:-default)",
+                ),
+            }
+        }
+
+        for (index, file) in config.main.required_files.iter().enumerate() {
+            if let Err(e) = file.check() {
+                let index = index.to_string();
+                self.error(
+                    &["main", "required_files", &index],
+                    e,
+                    "give a path relative to kern_root and a sha256 as printed by sha256sum",
+                );
+            }
+        }
+
+        if config.main.user_root.trim().is_empty() {
+            self.error(
+                &["main", "user_root"],
+                "main.user_root is empty",
+                "set it to the user directory, e.g. \"$(devroot)/User\"",
+            );
+        } else {
+            match expand_variables(&config.main.user_root, vars) {
+                Ok(user_root) => self.check_user_root(config, vars, Path::new(&user_root)),
+                Err(e) => self.error(
+                    &["main", "user_root"],
+                    format!("failed to expand main.user_root: {}", e),
+                    "define the variable in [vars] or give it a default with $(name:-default)",
+                ),
+            }
+        }
+    }
+
+    fn check_user_root(&mut self, config: &CozyBootConfig, vars: &Variables, user_root: &Path) {
+        for (index, entry) in config.main.user_skeleton.iter().enumerate() {
+            if let Err(e) = user_root::check_skeleton_entry(entry) {
+                let index = index.to_string();
+                self.error(
+                    &["main", "user_skeleton", &index],
+                    e,
+                    "list directories relative to user_root, e.g. \"home\"",
+                );
+            }
+        }
+
+        let template = match config
+            .main
+            .user_root_template
+            .as_deref()
+            .map(|template| expand_variables(template, vars))
+        {
+            Some(Ok(template)) if !Path::new(&template).is_dir() => {
+                self.error(
+                    &["main", "user_root_template"],
+                    format!("user root template '{}' is not a directory", template),
+                    "point it at a directory tree to copy, or remove it",
+                );
+                None
+            }
+            Some(Ok(template)) => Some(template),
+            Some(Err(e)) => {
+                self.error(
+                    &["main", "user_root_template"],
+                    format!("failed to expand main.user_root_template: {}", e),
+                    "define the variable in [vars] or give it a default with $(name:-default)",
+                );
+                None
+            }
+            None => None,
+        };
+
+        if let Err(e) = snapshot::resolve_dir(config.main.snapshot_dir.as_deref(), vars, user_root)
+        {
+            self.error(
+                &["main", "snapshot_dir"],
+                e,
+                "define the variable in [vars] or give it a default with $(name:-default)",
+            );
+        }
+        if config.main.snapshot_on_boot && config.main.snapshot_keep == 0 {
+            self.error(
+                &["main", "snapshot_keep"],
+                "main.snapshot_keep is 0, so every snapshot would be deleted right away",
+                "keep at least one snapshot, or turn snapshot_on_boot off",
+            );
+        }
+
+        let expanded_from = format!("expanded from '{}'", config.main.user_root);
+        if !user_root.exists() {
+            match template {
+                Some(template) => self.warning(&["main", "user_root"],
+                    format!("user root '{}' does not exist yet ({})", user_root.display(), expanded_from),
+                    format!("it will be created from '{}' on the next boot", template)),
+                None => self.error(&["main", "user_root"],
+                    format!("user root '{}' does not exist ({})", user_root.display(), expanded_from),
+                    "create the directory, fix the path, or set main.user_root_template to provision it"),
+            }
+        } else if !user_root.is_dir() {
+            self.error(
+                &["main", "user_root"],
+                format!(
+                    "user root '{}' is not a directory ({})",
+                    user_root.display(),
+                    expanded_from
+                ),
+                "point user_root at a directory",
+            );
+        } else if !user_root::is_writable(user_root) {
+            self.error(
+                &["main", "user_root"],
+                format!(
+                    "user root '{}' is not writable ({})",
+                    user_root.display(),
+                    expanded_from
+                ),
+                "CozyOS writes to its user root; fix the permissions or use another directory",
+            );
+        } else {
+            let missing = user_root::missing_skeleton(user_root, &config.main.user_skeleton);
+            if !missing.is_empty() {
+                self.error(&["main", "user_skeleton"],
+                    format!("user root '{}' is missing {}", user_root.display(), missing.join(", ")),
+                    "create the directories, or remove the user root so user_root_template recreates it");
+            }
+        }
+    }
+
+    fn check_runtime(&mut self, config: &CozyBootConfig, vars: &Variables) {
+        if config.runtime.executable.is_some() {
+            if let Err(e) = runtime::resolve_executable(config.runtime.executable.as_deref(), vars)
+            {
+                self.error(&["runtime", "executable"], e,
+                    "point it at a built cozy-os binary, or remove it to search $(devroot)/target and PATH");
+            }
+        }
+
+        if let Err(e) = backend::select(&config.runtime) {
+            let key = if config.runtime.template.is_some() {
+                "template"
+            } else {
+                "backend"
+            };
+            self.error(
+                &["runtime", key],
+                e,
+                "give runtime.template an argv list such as [\"{executable}\", \"{args}\"]",
+            );
+        }
+
+        for (key, value) in [
+            ("min_version", &config.runtime.min_version),
+            ("max_version", &config.runtime.max_version),
+        ] {
+            if let Some(value) = value
+                .as_deref()
+                .filter(|value| Version::parse(value).is_none())
+            {
+                self.error(
+                    &["runtime", key],
+                    format!("runtime.{} '{}' is not a version", key, value),
+                    "use a dotted number such as \"0.4\" or \"1.2.3\"",
+                );
+            }
+        }
+
+        if let (Some(min), Some(max)) = (
+            config
+                .runtime
+                .min_version
+                .as_deref()
+                .and_then(Version::parse),
+            config
+                .runtime
+                .max_version
+                .as_deref()
+                .and_then(Version::parse),
+        ) {
+            if !min.at_most(&max) {
+                self.error(
+                    &["runtime", "min_version"],
+                    format!(
+                        "runtime.min_version {} is newer than runtime.max_version {}",
+                        min, max
+                    ),
+                    "swap the two values or widen the range",
+                );
+            }
+        }
+
+        if config.runtime.ready_timeout.is_some() && config.runtime.ready_text.is_none() {
+            self.error(
+                &["runtime", "ready_timeout"],
+                "runtime.ready_timeout is set but runtime.ready_text is not",
+                "set ready_text to a line CozyOS prints once booted, e.g. \"login:\"",
+            );
+        }
+    }
+
+    fn check_supervisor(&mut self, config: &CozyBootConfig) {
+        let supervisor = &config.supervisor;
+        if supervisor.restart == RestartPolicy::Never {
+            return;
+        }
+
+        if supervisor.max_restarts == 0 {
+            self.warning(&["supervisor", "max_restarts"],
+                format!("supervisor.restart is \"{}\" but max_restarts is 0, so nothing is ever restarted",
+                    if supervisor.restart == RestartPolicy::Always { "always" } else { "on-failure" }),
+                "raise max_restarts or set restart = \"never\"");
+        }
+        if supervisor.backoff > supervisor.max_backoff {
+            self.warning(
+                &["supervisor", "backoff"],
+                format!(
+                    "supervisor.backoff {} is longer than supervisor.max_backoff {}",
+                    supervisor.backoff, supervisor.max_backoff
+                ),
+                "every restart waits max_backoff; lower backoff or raise max_backoff",
+            );
+        }
+    }
+
+    fn check_build(&mut self, config: &CozyBootConfig, vars: &Variables) {
+        let Some(build) = &config.build else { return };
+        if build.command.trim().is_empty() {
+            self.error(
+                &["build", "command"],
+                "build.command is empty",
+                "give it the shell command that builds the kernel, e.g. \"make kernel\"",
+            );
+        }
+        let Ok(kern_root) = vars.expand(&config.main.kern_root) else {
+            return;
+        };
+
+        match build.resolve(vars, &kern_root) {
+            Err(e) => self.error(&["build"], e,
+                "write $$(...) for shell command substitution, and keep build.output relative to kern_root"),
+            Ok(plan) if plan.inputs.is_empty() && !build.inputs.is_empty() => {
+                self.warning(&["build", "inputs"], "no file matches build.inputs, so only a missing output triggers a build",
+                    "check the glob patterns, e.g. \"$(devroot)/kernel/src/**/*.rs\"");
+            }
+            Ok(_) => {}
+        }
+    }
+
+    fn check_logging(&mut self, config: &CozyBootConfig, vars: &Variables) {
+        if let Err(e) = config.logging.resolve_dir(vars) {
+            self.error(
+                &["logging", "dir"],
+                e,
+                "define the variable in [vars] or give it a default with $(name:-default)",
+            );
+        }
+        if config.logging.max_files == 0 {
+            self.error(
+                &["logging", "max_files"],
+                "logging.max_files is 0, so no log could be kept",
+                "keep at least one file, or remove logging.dir to turn logging off",
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    // Validate `extra` appended to a config whose roots exist, returning each
+    // diagnostic as `<severity> <line>:<column> <message>`
+    fn diagnostics(tmp: &TempDir, extra: &str) -> Vec<String> {
+        let path = tmp.join("cozyboot.toml");
+        let content = format!(
+            "[main]\nkern_root = \"{}\"\nuser_root = \"{}\"\n[bootargs]\n[bin]\n{}",
+            tmp.join("System/kern").display(),
+            tmp.join("User").display(),
+            extra
+        );
+        std::fs::write(&path, content).unwrap();
+        let loaded = LoadedConfig::load(Some(&path), None).unwrap();
+        validate(&loaded)
+            .diagnostics
+            .iter()
+            .map(|diagnostic| {
+                let location = diagnostic.location.as_ref().map_or_else(
+                    || "-".to_string(),
+                    |location| format!("{}:{}", location.line, location.column),
+                );
+                format!(
+                    "{} {} {}",
+                    diagnostic.severity, location, diagnostic.message
+                )
+            })
+            .collect()
+    }
+
+    fn roots() -> TempDir {
+        TempDir::new("validate").with_files(&["System/kern/kernel", "User/home"])
+    }
+
+    #[test]
+    fn accepts_a_minimal_config() {
+        assert_eq!(diagnostics(&roots(), ""), Vec::<String>::new());
+    }
+
+    #[test]
+    fn points_at_the_mount_entry_at_fault() {
+        let tmp = roots();
+        let extra = format!(
+            "\n[[mounts]]\nhost_path = \"{0}\"\nguest_path = \"/data\"\n\n\
+             [[mounts]]\nhost_path = \"{0}\"\nguest_path = \"/data/sub\"\n",
+            tmp.path().display()
+        );
+        let found = diagnostics(&tmp, &extra);
+        assert_eq!(found.len(), 1);
+        assert!(
+            found[0].starts_with("error 13:1 mount guest path '/data/sub' overlaps"),
+            "{}",
+            found[0]
+        );
+    }
+
+    #[test]
+    fn keys_in_multi_line_strings_do_not_move_the_location() {
+        let found = diagnostics(
+            &roots(),
+            "[vars]\nnote = \"\"\"\nready_timeout = 1\n\"\"\"\n[runtime]\nready_timeout = \"5s\"\n",
+        );
+        assert_eq!(
+            found,
+            ["error 11:1 runtime.ready_timeout is set but runtime.ready_text is not"]
+        );
+    }
+
+    #[test]
+    fn reports_type_errors_and_unknown_keys_where_they_are() {
+        let found = diagnostics(&roots(), "[runtime]\ncolour = \"red\"\ntimeout = true\n");
+        assert_eq!(found.len(), 2, "{:?}", found);
+        assert!(found[0].starts_with("warning 7:1 unknown key 'runtime.colour'"));
+        assert!(found[1].starts_with("error 8:1 invalid value for 'runtime.timeout'"));
+    }
+
+    #[test]
+    fn version_ranges_compare_only_the_parts_of_the_maximum() {
+        let tmp = roots();
+        let allowed = "[runtime]\nmin_version = \"0.9.2\"\nmax_version = \"0.9\"\n";
+        assert_eq!(diagnostics(&tmp, allowed), Vec::<String>::new());
+        let reversed = "[runtime]\nmin_version = \"0.10\"\nmax_version = \"0.9\"\n";
+        assert_eq!(
+            diagnostics(&tmp, reversed),
+            ["error 7:1 runtime.min_version 0.10 is newer than runtime.max_version 0.9"]
+        );
+    }
+}
@@ -0,0 +1,280 @@
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// Errors produced while expanding `$(name)` references
+#[derive(Debug)]
+pub enum VarError {
+    /// A referenced variable is not defined anywhere and has no default
+    Undefined(String),
+    /// A variable refers back to itself, directly or through other variables
+    Cycle(Vec<String>),
+    /// A `$(` without its closing `)`
+    Unterminated(String),
+}
+
+impl fmt::Display for VarError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VarError::Undefined(name) => write!(f, "undefined variable '$({})'", name),
+            VarError::Cycle(chain) => write!(f, "variable cycle: {}", chain.join(" -> ")),
+            VarError::Unterminated(input) => write!(f, "unterminated '$(' in '{}'", input),
+        }
+    }
+}
+
+impl std::error::Error for VarError {}
+
+/// Variables available to `$(name)` references in the config.
+///
+/// Lookup order is: the `[vars]` table, then the built-ins (`devroot`, `home`,
+/// `config_dir`, `kern_root`), then the process environment.
+#[derive(Debug, Default)]
+pub struct Variables {
+    // Definitions that may themselves contain references
+    definitions: HashMap<String, String>,
+    // Fixed values that are used as-is
+    builtins: HashMap<String, String>,
+    config_dir: PathBuf,
+}
+
+impl Variables {
+    pub fn new(user_vars: &HashMap<String, String>, kern_root: &str, config_path: &Path) -> Self {
+        let mut builtins = HashMap::new();
+
+        // Handle $(devroot) variable - default to current directory if not set
+        let devroot = env::var("DEVROOT").unwrap_or_else(|_| ".".to_string());
+        builtins.insert("devroot".to_string(), devroot);
+
+        if let Some(home) = dirs::home_dir() {
+            builtins.insert("home".to_string(), home.to_string_lossy().to_string());
+        }
+
+        // $(config_dir) is the directory holding the active config file
+        let config_dir = config_path
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        builtins.insert(
+            "config_dir".to_string(),
+            config_dir.to_string_lossy().to_string(),
+        );
+
+        let mut definitions = user_vars.clone();
+        definitions
+            .entry("kern_root".to_string())
+            .or_insert_with(|| kern_root.to_string());
+
+        Variables {
+            definitions,
+            builtins,
+            config_dir: config_dir.to_path_buf(),
+        }
+    }
+
+    /// The directory holding the active config file, which relative paths
+    /// in it are read from
+    pub fn config_dir(&self) -> &Path {
+        &self.config_dir
+    }
+
+    /// Expand every `$(name)` and `$(name:-default)` reference in `input`.
+    /// `$$` produces a literal `$`.
+    pub fn expand(&self, input: &str) -> Result<String, VarError> {
+        self.expand_with_stack(input, &mut Vec::new())
+    }
+
+    fn expand_with_stack(&self, input: &str, stack: &mut Vec<String>) -> Result<String, VarError> {
+        let mut result = String::with_capacity(input.len());
+        let mut rest = input;
+
+        while let Some(pos) = rest.find('$') {
+            result.push_str(&rest[..pos]);
+            let after = &rest[pos + 1..];
+
+            if let Some(stripped) = after.strip_prefix('$') {
+                result.push('$');
+                rest = stripped;
+            } else if let Some(body_start) = after.strip_prefix('(') {
+                let end = find_closing_paren(body_start)
+                    .ok_or_else(|| VarError::Unterminated(input.to_string()))?;
+                let body = &body_start[..end];
+                result.push_str(&self.resolve_reference(body, stack)?);
+                rest = &body_start[end + 1..];
+            } else {
+                result.push('$');
+                rest = after;
+            }
+        }
+
+        result.push_str(rest);
+        Ok(result)
+    }
+
+    // Resolve the text between `$(` and `)`
+    fn resolve_reference(&self, body: &str, stack: &mut Vec<String>) -> Result<String, VarError> {
+        let (name, default) = match body.split_once(":-") {
+            Some((name, default)) => (name.trim(), Some(default)),
+            None => (body.trim(), None),
+        };
+
+        match self.lookup(name, stack)? {
+            Some(value) => Ok(value),
+            None => match default {
+                Some(default) => self.expand_with_stack(default, stack),
+                None => Err(VarError::Undefined(name.to_string())),
+            },
+        }
+    }
+
+    fn lookup(&self, name: &str, stack: &mut Vec<String>) -> Result<Option<String>, VarError> {
+        if let Some(definition) = self.definitions.get(name) {
+            if stack.iter().any(|seen| seen == name) {
+                let mut chain = stack.clone();
+                chain.push(name.to_string());
+                return Err(VarError::Cycle(chain));
+            }
+
+            stack.push(name.to_string());
+            let value = self.expand_with_stack(definition, stack);
+            stack.pop();
+            return value.map(Some);
+        }
+
+        if let Some(value) = self.builtins.get(name) {
+            return Ok(Some(value.clone()));
+        }
+
+        Ok(env::var(name).ok())
+    }
+}
+
+// Find the `)` matching an already consumed `(`, allowing nested references
+fn find_closing_paren(input: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (idx, ch) in input.char_indices() {
+        match ch {
+            '(' => depth += 1,
+            ')' if depth == 0 => return Some(idx),
+            ')' => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Expand $(...) references in a path and make it absolute when it exists
+pub fn expand_variables(path: &str, vars: &Variables) -> Result<String, VarError> {
+    let result = vars.expand(path)?;
+
+    // Convert to absolute path if it's relative
+    if let Ok(absolute_path) = std::fs::canonicalize(&result) {
+        Ok(absolute_path.to_string_lossy().to_string())
+    } else {
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(definitions: &[(&str, &str)]) -> Variables {
+        let definitions = definitions
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        let builtins = HashMap::from([("devroot".to_string(), "/dev/root".to_string())]);
+        Variables {
+            definitions,
+            builtins,
+            config_dir: PathBuf::from("/etc"),
+        }
+    }
+
+    #[test]
+    fn expands_definitions_and_builtins() {
+        let vars = vars(&[("src", "$(devroot)/src"), ("kernel", "$(src)/kernel")]);
+        assert_eq!(
+            vars.expand("$(kernel)/main.c").unwrap(),
+            "/dev/root/src/kernel/main.c"
+        );
+        assert_eq!(vars.expand("$( devroot )").unwrap(), "/dev/root");
+        assert_eq!(vars.expand("no references").unwrap(), "no references");
+    }
+
+    #[test]
+    fn definitions_shadow_builtins() {
+        let vars = vars(&[("devroot", "/elsewhere")]);
+        assert_eq!(vars.expand("$(devroot)").unwrap(), "/elsewhere");
+    }
+
+    #[test]
+    fn defaults_apply_only_to_undefined_names() {
+        let vars = vars(&[("out", "build")]);
+        assert_eq!(vars.expand("$(out:-target)").unwrap(), "build");
+        assert_eq!(
+            vars.expand("$(cozyboot_test_unset:-target)").unwrap(),
+            "target"
+        );
+        assert_eq!(
+            vars.expand("$(cozyboot_test_unset:-$(out)/x)").unwrap(),
+            "build/x"
+        );
+        assert_eq!(vars.expand("$(cozyboot_test_unset:-)").unwrap(), "");
+    }
+
+    #[test]
+    fn dollars_escape_and_pass_through() {
+        let vars = vars(&[]);
+        assert_eq!(
+            vars.expand("$$(date) costs $5").unwrap(),
+            "$(date) costs $5"
+        );
+        assert_eq!(vars.expand("a$").unwrap(), "a$");
+    }
+
+    #[test]
+    fn reports_undefined_and_unterminated() {
+        let vars = vars(&[]);
+        assert!(
+            matches!(vars.expand("$(cozyboot_test_unset)"), Err(VarError::Undefined(name)) if name == "cozyboot_test_unset")
+        );
+        assert!(matches!(
+            vars.expand("$(devroot"),
+            Err(VarError::Unterminated(_))
+        ));
+    }
+
+    #[test]
+    fn detects_cycles() {
+        let vars = vars(&[
+            ("a", "$(b)"),
+            ("b", "x/$(c)"),
+            ("c", "$(a)"),
+            ("me", "$(me)"),
+        ]);
+        let Err(VarError::Cycle(chain)) = vars.expand("$(a)") else {
+            panic!("expected a cycle")
+        };
+        assert_eq!(chain, ["a", "b", "c", "a"]);
+        assert_eq!(
+            vars.expand("$(me)").unwrap_err().to_string(),
+            "variable cycle: me -> me"
+        );
+    }
+
+    #[test]
+    fn repeated_references_are_not_cycles() {
+        let vars = vars(&[("a", "$(b)-$(b)"), ("b", "x")]);
+        assert_eq!(vars.expand("$(a) $(a)").unwrap(), "x-x x-x");
+    }
+
+    #[test]
+    fn config_dir_defaults_to_current_directory() {
+        let vars = Variables::new(&HashMap::new(), "/k", Path::new("boot.toml"));
+        assert_eq!(vars.config_dir(), Path::new("."));
+        assert_eq!(vars.expand("$(config_dir) $(kern_root)").unwrap(), ". /k");
+    }
+}